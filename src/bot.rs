@@ -0,0 +1,1306 @@
+//! The WeatherBot itself: construction from Args, --config reloads, saved state and the
+//! connection loop (reconnects, severe weather polling).
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use irc::client::prelude::*;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use rand::distributions::Alphanumeric;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::time::sleep;
+use futures_util::StreamExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::NamedTempFile;
+use crate::commands::{self, BotCommand};
+use crate::cli::{Args, ConditionKind, QuietHours, StateBackend, Units, parse_condition_color, parse_temp_emoji, temp_emoji_table};
+use crate::irc_handlers::{LastResult, MultilineLimits};
+use crate::weather::{CircuitState, Fetcher, Report, ReqwestFetcher};
+
+/// --config keys that "!wadmin reload" applies to the running bot; anything else needs a restart.
+const RELOADABLE_KEYS: &[&str] = &["admin", "condition_color", "quiet_hours", "temp_emoji", "use_notice"];
+
+/// Aborts a background task when the connection that spawned it goes away.
+struct TaskGuard(tokio::task::JoinHandle<()>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// When --state-file was last written and whether anything has changed since.
+#[derive(Default)]
+struct PersistSchedule {
+    last_write: Option<Instant>,
+    dirty: bool,
+}
+
+/// Collapses runs of identical log lines so a flapping connection doesn't flood the console.
+#[derive(Default)]
+struct RepeatCollapser {
+    last: Option<String>,
+    repeats: u32,
+}
+
+impl RepeatCollapser {
+    /// Returns the lines to print for `message`: new messages print immediately (after a
+    /// summary of how often the previous one repeated), repeats only every REPEAT_LOG_EVERY.
+    fn record(&mut self, message: String) -> Vec<String> {
+        if self.last.as_ref() == Some(&message) {
+            self.repeats += 1;
+            if self.repeats.is_multiple_of(REPEAT_LOG_EVERY) {
+                return vec![format!("{} (repeated {} times)", message, self.repeats)];
+            }
+            return Vec::new();
+        }
+
+        let mut lines = Vec::new();
+        if self.repeats > 0 {
+            lines.push(format!("(previous message repeated {} times)", self.repeats));
+        }
+        lines.push(message.clone());
+        self.last = Some(message);
+        self.repeats = 0;
+        lines
+    }
+}
+
+/// Where the bot is in its connection lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Connecting or registering with the server
+    Connecting,
+    /// The server has welcomed us (RPL_WELCOME); commands and alerts can be sent
+    Registered,
+    /// Disconnected and waiting out the reconnect delay
+    Reconnecting,
+}
+
+/// Why a connection that was established came to an end.
+#[derive(Clone, Debug, PartialEq)]
+enum Disconnect {
+    /// The stream ended without the server saying why, e.g. a dropped socket
+    Dropped,
+    /// The server closed the link with an ERROR message
+    ServerClosed(String),
+    /// The server's ERROR says we're banned; reconnecting would only be refused again
+    Banned(String),
+}
+
+/// A nick's saved location and when they last used it, for !wadmin prune.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedLocation {
+    pub location: String,
+    /// Unix seconds
+    pub last_used: i64,
+    /// Inferred from the location's country on the first lookup after it's saved; --units
+    /// when that lookup failed or found no country.
+    pub units: Option<Units>,
+}
+
+/// Everything users and operators have saved, as persisted to --state-file.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct SavedState {
+    nick_locations: HashMap<String, SavedLocation>,
+    nick_labels: HashMap<String, String>,
+    nick_units: HashMap<String, Units>,
+    nick_langs: HashMap<String, String>,
+    nick_timezones: HashMap<String, Tz>,
+    private_nicks: HashSet<String>,
+    channel_locations: HashMap<String, String>,
+    channel_langs: HashMap<String, String>,
+    /// Channel -> location -> number of successful lookups, for !wtop.
+    query_counts: HashMap<String, HashMap<String, u32>>,
+}
+
+/// Stored "!w units" choices; unrecognised entries are dropped.
+fn parse_nick_units(stored: HashMap<String, String>) -> HashMap<String, Units> {
+    stored.into_iter()
+        .filter_map(|(nick, units)| Some((nick, Units::from_str(&units, true).ok()?)))
+        .collect()
+}
+
+/// Stored "!w tz" choices; names chrono-tz no longer knows are dropped.
+fn parse_nick_timezones(stored: HashMap<String, String>) -> HashMap<String, Tz> {
+    stored.into_iter()
+        .filter_map(|(nick, timezone)| Some((nick, timezone.parse().ok()?)))
+        .collect()
+}
+
+trait StateStore: Send + Sync {
+    /// Returns empty state if nothing has been saved yet.
+    fn load(&self) -> Result<SavedState, Box<dyn Error>>;
+    fn save(&self, state: &SavedState) -> Result<(), Box<dyn Error>>;
+}
+
+/// Reads the JSON state format, shared by --state-file and the bundled defaults.
+fn parse_json_state(contents: &str) -> Result<SavedState, Box<dyn Error>> {
+    let file: Value = serde_json::from_str(contents)?;
+    let field = |key: &str| file.get(key).cloned().unwrap_or_else(|| json!({}));
+    let now = Utc::now().timestamp();
+    let mut nick_locations = HashMap::new();
+    for (nick, saved) in field("nick_locations").as_object().ok_or("nick_locations must be an object")? {
+        let saved = match saved {
+            // Files from before last-used tracking hold bare locations; count them as used now.
+            Value::String(location) => SavedLocation { location: location.clone(), last_used: now, units: None },
+            _ => SavedLocation {
+                location: saved["location"].as_str().ok_or_else(|| format!("no saved location for {}", nick))?.to_string(),
+                last_used: saved["last_used"].as_i64().unwrap_or(now),
+                units: saved["units"].as_str().and_then(|units| Units::from_str(units, true).ok()),
+            },
+        };
+        nick_locations.insert(nick.clone(), saved);
+    }
+    Ok(SavedState {
+        nick_locations,
+        nick_labels: serde_json::from_value(field("nick_labels"))?,
+        nick_units: parse_nick_units(serde_json::from_value(field("nick_units"))?),
+        nick_langs: serde_json::from_value(field("nick_langs"))?,
+        nick_timezones: parse_nick_timezones(serde_json::from_value(field("nick_timezones"))?),
+        private_nicks: serde_json::from_value(file.get("private_nicks").cloned().unwrap_or_else(|| json!([])))?,
+        channel_locations: serde_json::from_value(field("channel_locations"))?,
+        channel_langs: serde_json::from_value(field("channel_langs"))?,
+        query_counts: serde_json::from_value(field("query_counts"))?,
+    })
+}
+
+/// Until the state file exists, the bot starts from the bundled defaults; the first save
+/// writes them out along with whatever changed, and from then on the file wins.
+fn initial_state(existing: Option<&dyn StateStore>, bundled: &str) -> Result<SavedState, Box<dyn Error>> {
+    match existing {
+        Some(store) => store.load(),
+        None => parse_json_state(bundled),
+    }
+}
+
+/// The whole state as one JSON object, rewritten on every save.
+struct JsonStore(String);
+
+impl StateStore for JsonStore {
+    fn load(&self) -> Result<SavedState, Box<dyn Error>> {
+        let contents = match std::fs::read_to_string(&self.0) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SavedState::default()),
+            Err(e) => return Err(e.into()),
+        };
+        parse_json_state(&contents)
+    }
+
+    fn save(&self, state: &SavedState) -> Result<(), Box<dyn Error>> {
+        let mut private_nicks: Vec<&String> = state.private_nicks.iter().collect();
+        private_nicks.sort();
+        let nick_locations: serde_json::Map<String, Value> = state.nick_locations.iter()
+            .map(|(nick, saved)| (nick.clone(), json!({
+                "location": saved.location,
+                "last_used": saved.last_used,
+                "units": saved.units.map(Units::name),
+            })))
+            .collect();
+        let nick_units: HashMap<&String, &str> = state.nick_units.iter().map(|(nick, units)| (nick, units.name())).collect();
+        let nick_timezones: HashMap<&String, &str> = state.nick_timezones.iter().map(|(nick, timezone)| (nick, timezone.name())).collect();
+        let file = json!({
+            "nick_locations": nick_locations,
+            "nick_labels": state.nick_labels,
+            "nick_units": nick_units,
+            "nick_langs": state.nick_langs,
+            "nick_timezones": nick_timezones,
+            "private_nicks": private_nicks,
+            "channel_locations": state.channel_locations,
+            "channel_langs": state.channel_langs,
+            "query_counts": state.query_counts,
+        });
+        // Written beside the target and renamed over it so a crash can't leave a truncated file.
+        let temp_path = format!("{}.tmp", self.0);
+        std::fs::write(&temp_path, serde_json::to_string_pretty(&file)?)?;
+        std::fs::rename(&temp_path, &self.0)?;
+        Ok(())
+    }
+}
+
+/// One table per map, so individual entries can be queried directly.
+struct SqliteStore(Mutex<rusqlite::Connection>);
+
+impl SqliteStore {
+    fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS nick_locations (nick TEXT PRIMARY KEY, location TEXT NOT NULL, last_used INTEGER NOT NULL, units TEXT);
+             CREATE TABLE IF NOT EXISTS nick_labels (nick TEXT PRIMARY KEY, label TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS nick_units (nick TEXT PRIMARY KEY, units TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS nick_langs (nick TEXT PRIMARY KEY, lang TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS nick_timezones (nick TEXT PRIMARY KEY, timezone TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS private_nicks (nick TEXT PRIMARY KEY);
+             CREATE TABLE IF NOT EXISTS channel_locations (channel TEXT PRIMARY KEY, location TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS channel_langs (channel TEXT PRIMARY KEY, lang TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS query_counts (channel TEXT NOT NULL, location TEXT NOT NULL, count INTEGER NOT NULL, PRIMARY KEY (channel, location));",
+        )?;
+        // Databases from before last-used tracking lack the column; their entries count as used now.
+        if connection.prepare("SELECT last_used FROM nick_locations LIMIT 0").is_err() {
+            connection.execute(
+                &format!("ALTER TABLE nick_locations ADD COLUMN last_used INTEGER NOT NULL DEFAULT {}", Utc::now().timestamp()),
+                [],
+            )?;
+        }
+        if connection.prepare("SELECT units FROM nick_locations LIMIT 0").is_err() {
+            connection.execute("ALTER TABLE nick_locations ADD COLUMN units TEXT", [])?;
+        }
+        Ok(SqliteStore(Mutex::new(connection)))
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load(&self) -> Result<SavedState, Box<dyn Error>> {
+        let connection = self.0.lock().unwrap();
+        let pairs = |table: &str| -> Result<HashMap<String, String>, rusqlite::Error> {
+            let mut statement = connection.prepare(&format!("SELECT * FROM {}", table))?;
+            let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        };
+        let mut statement = connection.prepare("SELECT nick, location, last_used, units FROM nick_locations")?;
+        let nick_locations = statement.query_map([], |row| {
+            let units = row.get::<_, Option<String>>(3)?.and_then(|units| Units::from_str(&units, true).ok());
+            Ok((row.get(0)?, SavedLocation { location: row.get(1)?, last_used: row.get(2)?, units }))
+        })?.collect::<Result<HashMap<String, SavedLocation>, _>>()?;
+        let mut statement = connection.prepare("SELECT nick FROM private_nicks")?;
+        let private_nicks = statement.query_map([], |row| row.get(0))?.collect::<Result<HashSet<String>, _>>()?;
+        let mut query_counts: HashMap<String, HashMap<String, u32>> = HashMap::new();
+        let mut statement = connection.prepare("SELECT channel, location, count FROM query_counts")?;
+        for row in statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, u32>(2)?)))? {
+            let (channel, location, count) = row?;
+            query_counts.entry(channel).or_default().insert(location, count);
+        }
+        Ok(SavedState {
+            nick_locations,
+            nick_labels: pairs("nick_labels")?,
+            nick_units: parse_nick_units(pairs("nick_units")?),
+            nick_langs: pairs("nick_langs")?,
+            nick_timezones: parse_nick_timezones(pairs("nick_timezones")?),
+            private_nicks,
+            channel_locations: pairs("channel_locations")?,
+            channel_langs: pairs("channel_langs")?,
+            query_counts,
+        })
+    }
+
+    fn save(&self, state: &SavedState) -> Result<(), Box<dyn Error>> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM nick_locations", [])?;
+        for (nick, saved) in &state.nick_locations {
+            transaction.execute(
+                "INSERT INTO nick_locations VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![nick, saved.location, saved.last_used, saved.units.map(Units::name)],
+            )?;
+        }
+        transaction.execute("DELETE FROM nick_units", [])?;
+        for (nick, units) in &state.nick_units {
+            transaction.execute("INSERT INTO nick_units VALUES (?1, ?2)", [nick, units.name()])?;
+        }
+        transaction.execute("DELETE FROM nick_timezones", [])?;
+        for (nick, timezone) in &state.nick_timezones {
+            transaction.execute("INSERT INTO nick_timezones VALUES (?1, ?2)", [nick, timezone.name()])?;
+        }
+        for (table, entries) in [
+            ("nick_labels", &state.nick_labels),
+            ("nick_langs", &state.nick_langs),
+            ("channel_locations", &state.channel_locations),
+            ("channel_langs", &state.channel_langs),
+        ] {
+            transaction.execute(&format!("DELETE FROM {}", table), [])?;
+            for (key, value) in entries {
+                transaction.execute(&format!("INSERT INTO {} VALUES (?1, ?2)", table), [key, value])?;
+            }
+        }
+        transaction.execute("DELETE FROM private_nicks", [])?;
+        for nick in &state.private_nicks {
+            transaction.execute("INSERT INTO private_nicks VALUES (?1)", [nick])?;
+        }
+        transaction.execute("DELETE FROM query_counts", [])?;
+        for (channel, counts) in &state.query_counts {
+            for (location, count) in counts {
+                transaction.execute("INSERT INTO query_counts VALUES (?1, ?2, ?3)", rusqlite::params![channel, location, count])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+const RECONNECT_DELAY_SECS: f64 = 5.0;
+const RECONNECT_JITTER: f64 = 0.2;
+
+const REPEAT_LOG_EVERY: u32 = 100;
+
+/// How many of a channel's most looked-up locations --prewarm keeps cached.
+const PREWARM_TOP: usize = 5;
+
+/// State saves closer together than this are coalesced into one write.
+const PERSIST_DEBOUNCE_SECS: u64 = 5;
+
+/// Channel pins, labels and the like to start from when there's no state file yet; edit
+/// src/default_state.json before building to ship them.
+const BUNDLED_STATE: &str = include_str!("default_state.json");
+
+/// Phrases in a server ERROR that mean the ban will still be in place on reconnect.
+const BAN_MARKERS: &[&str] = &["k-lined", "g-lined", "z-lined", "banned"];
+
+/// (lowercased query, report, units, lang, home timezone) for the response cache.
+pub type ResponseKey = (String, Report, Units, Option<String>, Option<Tz>);
+
+/// A current temperature as (°F, °C).
+pub type Reading = (i32, i32);
+
+pub struct WeatherBot {
+    config: Config,
+    /// The CA certificate and client identity `config` points irc at; deleted with the bot.
+    _tls_files: Vec<NamedTempFile>,
+    pub nick_locations: Mutex<HashMap<String, SavedLocation>>,
+    /// Friendly names for a nick's saved location, dropped when the location changes.
+    pub nick_labels: Mutex<HashMap<String, String>>,
+    /// Units chosen with "!w units", kept when the nick's saved location changes.
+    pub nick_units: Mutex<HashMap<String, Units>>,
+    /// Condition-text languages chosen with "!w lang"; these beat the channel's and --lang.
+    pub nick_langs: Mutex<HashMap<String, String>>,
+    /// Home timezones chosen with "!w tz"; times in their replies are shown in these instead
+    /// of the location's local time.
+    pub nick_timezones: Mutex<HashMap<String, Tz>>,
+    /// Nicks whose saved location can't be looked up by others with "!w <nick>".
+    pub private_nicks: Mutex<HashSet<String>>,
+    pub channel_locations: Mutex<HashMap<String, String>>,
+    /// Condition-text languages set with !wlang for queries made in the channel.
+    pub channel_langs: Mutex<HashMap<String, String>>,
+    pub admins: Mutex<Vec<String>>,
+    pub units: Units,
+    pub lang: Option<String>,
+    pub quiet_hours: Mutex<Option<QuietHours>>,
+    pub timezone: Tz,
+    pub allow_channels: Vec<String>,
+    pub join_on_invite: bool,
+    pub multiline: bool,
+    /// Limits from the server's CAP LS, kept until it ACKs our request for the capability.
+    pub multiline_offer: Mutex<Option<MultilineLimits>>,
+    /// Set once draft/multiline is enabled on the current connection.
+    pub multiline_limits: Mutex<Option<MultilineLimits>>,
+    /// Whether "!w <city>" saves the nick's location; "!w set <city>" always does.
+    pub auto_save: bool,
+    /// Channels greeted with JOIN_GREETING when the bot joins them.
+    pub announce_channels: Vec<String>,
+    alert_channels: Vec<String>,
+    alert_interval: Duration,
+    status_city: Option<String>,
+    status_interval: Duration,
+    prewarm: bool,
+    prewarm_interval: Duration,
+    /// Last severe condition code announced per channel, so a storm is only announced once.
+    pub last_alerts: Mutex<HashMap<String, i32>>,
+    pub condition_colors: Mutex<HashMap<ConditionKind, String>>,
+    /// (°F threshold, emoji), ascending; empty means the built-in table.
+    pub temp_emojis: Mutex<Vec<(i32, String)>>,
+    pub use_notice: Mutex<bool>,
+    rng: Mutex<StdRng>,
+    pub fetch_semaphore: Semaphore,
+    /// Set from a 429's Retry-After; no requests go to wttr.in until it passes.
+    pub blocked_until: Mutex<Option<Instant>>,
+    pub circuit: Mutex<CircuitState>,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+    /// Fetches currently running, keyed by normalized query; identical requests wait on these.
+    pub in_flight: Mutex<HashMap<String, broadcast::Sender<Result<Value, String>>>>,
+    /// LINELEN from the server's ISUPPORT (005), if it advertised one.
+    pub line_len: Mutex<Option<usize>>,
+    /// CHANTYPES from the server's ISUPPORT (005), if it advertised one.
+    pub chantypes: Mutex<Option<String>>,
+    pub geocode: bool,
+    pub what3words_key: Option<String>,
+    pub marine: bool,
+    pub forecast_hour: u32,
+    pub max_forecast_days: usize,
+    pub strip_variation_selectors: bool,
+    pub wind_arrows: bool,
+    pub dry_dewpoint: i32,
+    pub jacket_below: i32,
+    pub shorts_above: i32,
+    pub windy_mph: i32,
+    pub umbrella_chance: u32,
+    pub muggy_dewpoint: i32,
+    pub degree_day_base: i32,
+    /// Place name -> "lat,lon" for names the geocoder has resolved.
+    pub geocode_cache: Mutex<HashMap<String, String>>,
+    pub climate_normals: bool,
+    /// "lat,lon MM-DD" -> average high in Celsius; the archive query is large, so each is fetched once.
+    pub normals_cache: Mutex<HashMap<String, f64>>,
+    pub fetcher: Box<dyn Fetcher>,
+    /// Commands matched before the built-in ones in `handle_message`.
+    pub commands: Vec<Box<dyn BotCommand>>,
+    pub last_results: Mutex<HashMap<String, LastResult>>,
+    /// Lowercased query -> the current temperature from its latest full report.
+    pub current_readings: Mutex<HashMap<String, Reading>>,
+    /// Nick -> the location and temperature they were last shown, for "+4°F since last check".
+    pub nick_readings: Mutex<HashMap<String, (String, Reading)>>,
+    /// Formatted replies and when they were made. Everything else that shapes a reply is
+    /// bot-wide config, so a config reload clears it.
+    pub response_cache: Mutex<HashMap<ResponseKey, (String, Instant)>>,
+    pub config_path: Option<String>,
+    state: Mutex<ConnectionState>,
+    /// The last ERROR the server sent on the current connection, usually its reason for closing it.
+    pub last_server_error: Mutex<Option<String>>,
+    state_store: Option<Box<dyn StateStore>>,
+    pub query_counts: Mutex<HashMap<String, HashMap<String, u32>>>,
+    pub geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    pub command_timeout: Duration,
+    persist_schedule: Mutex<PersistSchedule>,
+}
+
+impl WeatherBot {
+    pub fn new(args: Args) -> Result<Self, Box<dyn Error>> {
+        let rng = match args.reconnect_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        // "#secret:thekey" joins #secret with key "thekey".
+        let (channels, channel_keys): (Vec<String>, HashMap<String, String>) = match args.channel.as_deref().map(|c| c.split_once(':')) {
+            Some(Some((channel, key))) => (vec![channel.to_string()], HashMap::from([(channel.to_string(), key.to_string())])),
+            _ => (args.channel.into_iter().collect(), HashMap::new()),
+        };
+
+        let mut config = Config {
+            nickname: Some(args.nickname),
+            server: args.server,
+            port: Some(args.port),
+            channels,
+            channel_keys,
+            // irc PINGs the server itself and ends the stream with PingTimeout if no PONG comes back.
+            ping_time: Some(args.ping_interval),
+            ping_timeout: Some(args.ping_timeout),
+            use_tls: Some(args.use_tls),
+            dangerously_accept_invalid_certs: Some(args.insecure_skip_verify),
+            ..Config::default()
+        };
+
+        let mut tls_files = Vec::new();
+        if let Some(root) = WeatherBot::load_ca_cert(&args.ca_cert)? {
+            let root_file = WeatherBot::tls_file(&root.to_der()?)?;
+            config.cert_path = Some(root_file.path().to_string_lossy().into_owned());
+            tls_files.push(root_file);
+        }
+
+        if args.insecure_skip_verify {
+            eprintln!("WARNING: --insecure-skip-verify is set. TLS certificates will NOT be verified and the connection can be intercepted.");
+        }
+
+        if let (Some(cert_path), Some(key_path)) = (&args.client_cert, &args.client_key) {
+            let (identity_file, password) = WeatherBot::load_client_identity(cert_path, key_path)?;
+            config.client_cert_path = Some(identity_file.path().to_string_lossy().into_owned());
+            config.client_cert_pass = Some(password);
+            tls_files.push(identity_file);
+        }
+
+        let first_run = args.state_file.as_ref().is_none_or(|path| !Path::new(path).exists());
+        let state_store: Option<Box<dyn StateStore>> = match (&args.state_file, args.state_backend) {
+            (Some(path), StateBackend::Json) => Some(Box::new(JsonStore(path.clone()))),
+            (Some(path), StateBackend::Sqlite) => Some(Box::new(SqliteStore::open(path)?)),
+            (None, _) => None,
+        };
+        let saved = initial_state(state_store.as_deref().filter(|_| !first_run), BUNDLED_STATE)?;
+
+        let bot = WeatherBot {
+            config,
+            _tls_files: tls_files,
+            nick_locations: Mutex::new(saved.nick_locations),
+            nick_labels: Mutex::new(saved.nick_labels),
+            nick_units: Mutex::new(saved.nick_units),
+            nick_langs: Mutex::new(saved.nick_langs),
+            nick_timezones: Mutex::new(saved.nick_timezones),
+            private_nicks: Mutex::new(saved.private_nicks),
+            channel_locations: Mutex::new(saved.channel_locations),
+            channel_langs: Mutex::new(saved.channel_langs),
+            query_counts: Mutex::new(saved.query_counts),
+            command_timeout: Duration::from_secs(args.command_timeout),
+            persist_schedule: Mutex::new(PersistSchedule::default()),
+            geoip: args.geoip_db.as_deref().map(maxminddb::Reader::open_readfile).transpose()?,
+            admins: Mutex::new(args.admin),
+            units: args.units,
+            lang: args.lang,
+            quiet_hours: Mutex::new(args.quiet_hours),
+            timezone: args.timezone,
+            allow_channels: args.allow_channel,
+            join_on_invite: args.join_on_invite,
+            multiline: args.multiline,
+            multiline_offer: Mutex::new(None),
+            multiline_limits: Mutex::new(None),
+            auto_save: !args.no_auto_save,
+            announce_channels: args.announce_on_join,
+            alert_channels: args.alert_channel,
+            alert_interval: Duration::from_secs(args.alert_interval),
+            status_city: args.status_city,
+            status_interval: Duration::from_secs(args.status_interval),
+            prewarm: args.prewarm,
+            prewarm_interval: Duration::from_secs(args.prewarm_interval),
+            last_alerts: Mutex::new(HashMap::new()),
+            condition_colors: Mutex::new(args.condition_color.into_iter().collect()),
+            temp_emojis: Mutex::new(temp_emoji_table(args.temp_emoji)),
+            use_notice: Mutex::new(args.use_notice),
+            rng: Mutex::new(rng),
+            fetch_semaphore: Semaphore::new(args.max_concurrent_fetches),
+            blocked_until: Mutex::new(None),
+            circuit: Mutex::new(CircuitState::Closed { failures: 0 }),
+            breaker_threshold: args.breaker_threshold,
+            breaker_cooldown: Duration::from_secs(args.breaker_cooldown),
+            in_flight: Mutex::new(HashMap::new()),
+            line_len: Mutex::new(None),
+            chantypes: Mutex::new(None),
+            geocode: args.geocode,
+            what3words_key: args.what3words_key,
+            marine: args.marine,
+            forecast_hour: args.forecast_hour,
+            max_forecast_days: args.max_forecast_days,
+            strip_variation_selectors: args.strip_variation_selectors,
+            wind_arrows: !args.no_wind_arrows,
+            dry_dewpoint: args.dry_dewpoint,
+            jacket_below: args.jacket_below,
+            shorts_above: args.shorts_above,
+            windy_mph: args.windy_mph,
+            umbrella_chance: args.umbrella_chance,
+            muggy_dewpoint: args.muggy_dewpoint,
+            degree_day_base: args.degree_day_base,
+            geocode_cache: Mutex::new(HashMap::new()),
+            climate_normals: args.climate_normals,
+            normals_cache: Mutex::new(HashMap::new()),
+            // Capped per request too, so background fetches that skip with_timeout (alerts,
+            // prewarming, the status line) can't hang on a stalled connection either.
+            fetcher: Box::new(ReqwestFetcher(reqwest::Client::builder().timeout(Duration::from_secs(args.command_timeout)).build()?)),
+            commands: commands::registry(),
+            last_results: Mutex::new(HashMap::new()),
+            current_readings: Mutex::new(HashMap::new()),
+            nick_readings: Mutex::new(HashMap::new()),
+            response_cache: Mutex::new(HashMap::new()),
+            config_path: args.config,
+            state: Mutex::new(ConnectionState::Connecting),
+            last_server_error: Mutex::new(None),
+            state_store,
+        };
+
+        if let Some(path) = &bot.config_path {
+            let restart_keys = bot.reload_config(path)?;
+            if !restart_keys.is_empty() {
+                eprintln!("Warning: {} in {} can't be set from the config file; use the command-line flags.", restart_keys.join(", "), path);
+            }
+        }
+        Ok(bot)
+    }
+
+    /// Re-reads the --config file and applies its hot-reloadable settings. Nothing is applied
+    /// if any value is invalid. Returns the keys present that only take effect on restart.
+    pub fn reload_config(&self, path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let file: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let settings = file.as_object().ok_or_else(|| format!("{} must contain a JSON object", path))?;
+
+        let admins = settings.get("admin")
+            .map(|v| serde_json::from_value::<Vec<String>>(v.clone()))
+            .transpose()?;
+        let condition_colors = settings.get("condition_color")
+            .map(|v| serde_json::from_value::<Vec<String>>(v.clone()))
+            .transpose()?
+            .map(|colors| colors.iter().map(|c| parse_condition_color(c)).collect::<Result<HashMap<_, _>, _>>())
+            .transpose()?;
+        let temp_emojis = settings.get("temp_emoji")
+            .map(|v| serde_json::from_value::<Vec<String>>(v.clone()))
+            .transpose()?
+            .map(|table| table.iter().map(|entry| parse_temp_emoji(entry)).collect::<Result<Vec<_>, _>>())
+            .transpose()?;
+        let quiet_hours = settings.get("quiet_hours")
+            .map(|v| serde_json::from_value::<Option<String>>(v.clone()))
+            .transpose()?
+            .map(|window| window.map(|w| w.parse::<QuietHours>()).transpose())
+            .transpose()?;
+        let use_notice = settings.get("use_notice")
+            .map(|v| serde_json::from_value::<bool>(v.clone()))
+            .transpose()?;
+
+        if let Some(admins) = admins {
+            *self.admins.lock().unwrap() = admins;
+        }
+        if let Some(condition_colors) = condition_colors {
+            *self.condition_colors.lock().unwrap() = condition_colors;
+        }
+        if let Some(temp_emojis) = temp_emojis {
+            *self.temp_emojis.lock().unwrap() = temp_emoji_table(temp_emojis);
+        }
+        if let Some(quiet_hours) = quiet_hours {
+            *self.quiet_hours.lock().unwrap() = quiet_hours;
+        }
+        if let Some(use_notice) = use_notice {
+            *self.use_notice.lock().unwrap() = use_notice;
+        }
+        self.response_cache.lock().unwrap().clear();
+
+        let mut restart_keys: Vec<String> = settings.keys()
+            .filter(|key| !RELOADABLE_KEYS.contains(&key.as_str()))
+            .cloned()
+            .collect();
+        restart_keys.sort();
+        Ok(restart_keys)
+    }
+
+    /// irc's TLS connector only accepts a PKCS#12 bundle, so the PEM certificate and key are
+    /// repackaged into one under a random password and written to a tls_file.
+    fn load_client_identity(cert_path: &str, key_path: &str) -> Result<(NamedTempFile, String), Box<dyn Error>> {
+        let cert_pem = std::fs::read(cert_path)
+            .map_err(|e| format!("Could not read client certificate {}: {}", cert_path, e))?;
+        let key_pem = std::fs::read(key_path)
+            .map_err(|e| format!("Could not read client key {}: {}", key_path, e))?;
+
+        let mut certs = X509::stack_from_pem(&cert_pem)
+            .map_err(|e| format!("Invalid PEM certificate in {}: {}", cert_path, e))?
+            .into_iter();
+        let cert = certs.next().ok_or_else(|| format!("No PEM certificate found in {}", cert_path))?;
+        let key = PKey::private_key_from_pem(&key_pem)
+            .map_err(|e| format!("Invalid PEM private key in {}: {}", key_path, e))?;
+
+        let mut chain = Stack::new()?;
+        for ca in certs {
+            chain.push(ca)?;
+        }
+
+        let password: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        let pkcs12 = Pkcs12::builder()
+            .name("rirc")
+            .pkey(&key)
+            .cert(&cert)
+            .ca(chain)
+            .build2(&password)?;
+
+        Ok((WeatherBot::tls_file(&pkcs12.to_der()?)?, password))
+    }
+
+    /// irc reads its TLS material from files on every connect. Each is a new file with a random
+    /// name, readable only by us (the private key is in one), and removed when it's dropped.
+    fn tls_file(contents: &[u8]) -> Result<NamedTempFile, Box<dyn Error>> {
+        let mut file = tempfile::Builder::new().prefix("rirc-").tempfile()?;
+        file.write_all(contents)?;
+        file.flush()?;
+        Ok(file)
+    }
+
+    /// The one certificate across the --ca-cert files, ignoring copies of it. More than one is
+    /// refused rather than trusting only part of them, since irc's connector adds a single root.
+    fn load_ca_cert(paths: &[String]) -> Result<Option<X509>, Box<dyn Error>> {
+        let mut roots: Vec<X509> = Vec::new();
+        for path in paths {
+            let pem = std::fs::read(path).map_err(|e| format!("Could not read CA certificate {}: {}", path, e))?;
+            let certs = X509::stack_from_pem(&pem).map_err(|e| format!("Invalid PEM certificate in {}: {}", path, e))?;
+            if certs.is_empty() {
+                return Err(format!("No PEM certificate found in {}", path).into());
+            }
+            for cert in certs {
+                if !roots.iter().any(|root| root.to_der().ok() == cert.to_der().ok()) {
+                    roots.push(cert);
+                }
+            }
+        }
+        if roots.len() > 1 {
+            return Err(format!("--ca-cert holds {} different certificates, but only one extra CA can be trusted; keep just the root that signs the server's certificate", roots.len()).into());
+        }
+        Ok(roots.pop())
+    }
+
+    pub async fn run(self: &Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let mut log = RepeatCollapser::default();
+        let mut dropped = false;
+        loop {
+            let outcome = self.connect_and_run().await;
+            self.flush_state(true);
+            let retry_now = !dropped && matches!(outcome, Ok(Disconnect::Dropped));
+            dropped = matches!(outcome, Ok(Disconnect::Dropped));
+            let (message, delay) = match outcome {
+                // A dropped link is usually transient, so the first retry is immediate; a link
+                // that keeps dropping backs off like any other failure.
+                Ok(Disconnect::Dropped) if retry_now => ("Connection dropped. Reconnecting...".to_string(), Duration::ZERO),
+                Ok(Disconnect::Dropped) => ("Connection dropped again. Attempting to reconnect...".to_string(), self.reconnect_delay()),
+                Ok(Disconnect::ServerClosed(reason)) => {
+                    (format!("Server closed the connection: {}. Attempting to reconnect...", reason), self.reconnect_delay())
+                }
+                Ok(Disconnect::Banned(reason)) => {
+                    return Err(format!("Banned from {}: {}", self.config.server.as_deref().unwrap_or("<none>"), reason).into());
+                }
+                Err(e) => (format!("{}. Attempting to reconnect...", self.describe_connection_error(e.as_ref())), self.reconnect_delay()),
+            };
+            for line in log.record(message) {
+                println!("{}", line);
+            }
+            self.set_state(ConnectionState::Reconnecting);
+            sleep(delay).await;
+        }
+    }
+
+    /// Turns a connection failure into a message that tells DNS, refused and TLS failures apart.
+    fn describe_connection_error(&self, e: &(dyn Error + 'static)) -> String {
+        let server = self.config.server.as_deref().unwrap_or("<none>");
+        let port = self.config.port();
+        match e.downcast_ref::<irc::error::Error>() {
+            Some(irc::error::Error::Tls(tls)) => format!("TLS handshake with {}:{} failed: {}", server, port, tls),
+            Some(irc::error::Error::Io(io)) if io.kind() == ErrorKind::ConnectionRefused => {
+                format!("Connection to {}:{} refused", server, port)
+            }
+            Some(irc::error::Error::Io(io)) if io.to_string().contains("failed to lookup address") => {
+                format!("Can't resolve host {}: {}", server, io)
+            }
+            Some(irc::error::Error::Io(io)) => format!("Error connecting to {}:{}: {}", server, port, io),
+            Some(irc::error::Error::PingTimeout) => {
+                format!("No PONG from {} within {}s; connection presumed dead", server, self.config.ping_timeout())
+            }
+            _ => format!("Error: {}", e),
+        }
+    }
+
+    fn reconnect_delay(&self) -> Duration {
+        let jitter = self.rng.lock().unwrap().gen_range(-RECONNECT_JITTER..=RECONNECT_JITTER);
+        Duration::from_secs_f64(RECONNECT_DELAY_SECS * (1.0 + jitter))
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn is_registered(&self) -> bool {
+        *self.state.lock().unwrap() == ConnectionState::Registered
+    }
+
+    async fn connect_and_run(self: &Arc<Self>) -> Result<Disconnect, Box<dyn Error>> {
+        self.set_state(ConnectionState::Connecting);
+        *self.last_server_error.lock().unwrap() = None;
+        let mut client = Client::from_config(self.config.clone()).await?;
+        *self.multiline_offer.lock().unwrap() = None;
+        *self.multiline_limits.lock().unwrap() = None;
+        if self.multiline {
+            // Ask what the server supports; draft/multiline is requested once it's offered.
+            client.send_cap_ls(NegotiationVersion::V302)?;
+        }
+        client.identify()?;
+
+        let mut stream = client.stream()?;
+
+        let _alerts = (!self.alert_channels.is_empty()).then(|| {
+            let bot = Arc::clone(self);
+            let sender = client.sender();
+            TaskGuard(tokio::spawn(async move { bot.poll_severe_weather(sender).await }))
+        });
+        let _prewarm = self.prewarm.then(|| {
+            let bot = Arc::clone(self);
+            TaskGuard(tokio::spawn(async move { bot.prewarm_cache().await }))
+        });
+        let _status = self.status_city.clone().map(|city| {
+            let bot = Arc::clone(self);
+            let sender = client.sender();
+            TaskGuard(tokio::spawn(async move { bot.update_status(sender, &city).await }))
+        });
+
+        let mut flush_check = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    match message {
+                        Ok(message) => self.process_message(&client, message).await,
+                        // The deadline stays expired, so the stream would only keep repeating it.
+                        Err(e @ irc::error::Error::PingTimeout) => return Err(e.into()),
+                        Err(e) => eprintln!("Error receiving message: {}", e),
+                    }
+                }
+                _ = flush_check.tick() => self.flush_state(false),
+            }
+        }
+
+        let last_error = self.last_server_error.lock().unwrap().take();
+        Ok(self.classify_disconnect(last_error))
+    }
+
+    fn classify_disconnect(&self, last_error: Option<String>) -> Disconnect {
+        match last_error {
+            Some(reason) if BAN_MARKERS.iter().any(|marker| reason.to_lowercase().contains(marker)) => Disconnect::Banned(reason),
+            Some(reason) => Disconnect::ServerClosed(reason),
+            None => Disconnect::Dropped,
+        }
+    }
+
+    async fn poll_severe_weather(&self, sender: Sender) {
+        let mut interval = tokio::time::interval(self.alert_interval);
+        loop {
+            interval.tick().await;
+            if !self.is_registered() {
+                continue;
+            }
+            for channel in &self.alert_channels {
+                let Some(location) = self.channel_locations.lock().unwrap().get(channel).cloned() else {
+                    continue;
+                };
+                let lang = self.channel_langs.lock().unwrap().get(channel).cloned().or_else(|| self.lang.clone());
+                match self.get_weather(&location, lang.as_deref()).await {
+                    Ok(data) => {
+                        if let Some(alert) = self.check_severe(channel, &data, &location) {
+                            if let Err(e) = self.send_chunked(&sender, channel, &alert) {
+                                eprintln!("Error sending severe weather alert to {}: {}", channel, e);
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Error polling severe weather for {}: {}", channel, e),
+                }
+            }
+        }
+    }
+
+    /// Refreshes the cached standard report for every prewarm_targets entry each
+    /// --prewarm-interval. Lookups go through fetch like any other, so they share the
+    /// concurrency limit and stop while the circuit breaker is open.
+    async fn prewarm_cache(&self) {
+        let mut interval = tokio::time::interval(self.prewarm_interval);
+        loop {
+            interval.tick().await;
+            while !self.is_registered() {
+                sleep(Duration::from_secs(1)).await;
+            }
+            for (query, lang) in self.prewarm_targets() {
+                self.prewarm(&query, lang).await;
+            }
+        }
+    }
+
+    /// Each channel's pinned location and its PREWARM_TOP most looked-up ones, in the channel's
+    /// language, without duplicates.
+    pub fn prewarm_targets(&self) -> Vec<(String, Option<String>)> {
+        let mut channels: HashSet<String> = self.channel_locations.lock().unwrap().keys().cloned().collect();
+        channels.extend(self.query_counts.lock().unwrap().keys().cloned());
+        let mut seen = HashSet::new();
+        let mut targets = Vec::new();
+        for channel in channels {
+            let lang = self.channel_langs.lock().unwrap().get(&channel).cloned().or_else(|| self.lang.clone());
+            let pinned = self.channel_locations.lock().unwrap().get(&channel).cloned();
+            let top = self.top_locations(&channel, PREWARM_TOP).into_iter().map(|(location, _)| location);
+            for query in pinned.into_iter().chain(top) {
+                if seen.insert((query.to_lowercase(), lang.clone())) {
+                    targets.push((query, lang.clone()));
+                }
+            }
+        }
+        targets
+    }
+
+    /// Caches the standard report for `query` so the next !w for it is instant. Each requester
+    /// gets their own units ("!w units", or their saved location's country), so the one fetch
+    /// is formatted in every unit system rather than just --units.
+    pub async fn prewarm(&self, query: &str, lang: Option<String>) {
+        let report = Report::Full { hour: None };
+        match self.get_full_forecast(query, lang.as_deref()).await {
+            Ok(forecast) => {
+                let responses: Vec<(Units, String)> = Units::value_variants().iter()
+                    .map(|&units| (units, self.format_response(&forecast, query, self.forecast_hour, units)))
+                    .collect();
+                let mut cache = self.response_cache.lock().unwrap();
+                for (units, response) in responses {
+                    cache.insert((query.to_lowercase(), report, units, lang.clone(), None), (response, Instant::now()));
+                }
+            }
+            Err(e) => eprintln!("Error prewarming {}: {}", query, e),
+        }
+    }
+
+    /// Keeps the away message set to `city`'s current conditions, starting as soon as the
+    /// connection registers.
+    async fn update_status(&self, sender: Sender, city: &str) {
+        let query = self.normalize_location(city);
+        let mut interval = tokio::time::interval(self.status_interval);
+        loop {
+            interval.tick().await;
+            while !self.is_registered() {
+                sleep(Duration::from_secs(1)).await;
+            }
+            let status = match self.get_weather(&query, self.lang.as_deref()).await {
+                Ok(data) => self.status_command(&data, &query),
+                Err(e) => Err(e),
+            };
+            match status {
+                Ok(away) => {
+                    if let Err(e) = sender.send(away) {
+                        eprintln!("Error setting away status: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Error fetching weather for the away status: {}", e),
+            }
+        }
+    }
+
+    /// AWAY with the one-line conditions, e.g. "London: 15°C (feels 13°C), partly cloudy".
+    fn status_command(&self, data: &Value, query: &str) -> Result<Command, Box<dyn Error>> {
+        let forecast = self.parse_forecast(data)?;
+        Ok(Command::AWAY(Some(self.format_temp_line(&forecast, query, self.units))))
+    }
+
+    /// Writes the saved state out to --state-file, if one is configured. Within
+    /// PERSIST_DEBOUNCE_SECS of the last write it's only marked changed, and the connection's
+    /// once-a-second flush_state writes it when the window is up, so a burst costs one write.
+    pub fn persist_state(&self) {
+        if self.state_store.is_none() {
+            return;
+        }
+        let mut schedule = self.persist_schedule.lock().unwrap();
+        if schedule.last_write.is_some_and(|at| at.elapsed() < Duration::from_secs(PERSIST_DEBOUNCE_SECS)) {
+            schedule.dirty = true;
+            return;
+        }
+        self.write_state(&mut schedule);
+    }
+
+    /// Writes changes held back by persist_state once the debounce window is up, or right away
+    /// with `force` (e.g. before the connection is re-established).
+    pub fn flush_state(&self, force: bool) {
+        let mut schedule = self.persist_schedule.lock().unwrap();
+        let due = force || schedule.last_write.is_none_or(|at| at.elapsed() >= Duration::from_secs(PERSIST_DEBOUNCE_SECS));
+        if schedule.dirty && due {
+            self.write_state(&mut schedule);
+        }
+    }
+
+    fn write_state(&self, schedule: &mut PersistSchedule) {
+        let Some(store) = &self.state_store else {
+            return;
+        };
+        schedule.last_write = Some(Instant::now());
+        schedule.dirty = false;
+        let state = SavedState {
+            nick_locations: self.nick_locations.lock().unwrap().clone(),
+            nick_labels: self.nick_labels.lock().unwrap().clone(),
+            nick_units: self.nick_units.lock().unwrap().clone(),
+            nick_langs: self.nick_langs.lock().unwrap().clone(),
+            nick_timezones: self.nick_timezones.lock().unwrap().clone(),
+            private_nicks: self.private_nicks.lock().unwrap().clone(),
+            channel_locations: self.channel_locations.lock().unwrap().clone(),
+            channel_langs: self.channel_langs.lock().unwrap().clone(),
+            query_counts: self.query_counts.lock().unwrap().clone(),
+        };
+        if let Err(e) = store.save(&state) {
+            eprintln!("Error saving state: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weather::testing::*;
+    use clap::Parser;
+
+    #[test]
+    fn seeded_reconnect_jitter_repeats() {
+        let delays = |bot: &WeatherBot| (0..5).map(|_| bot.reconnect_delay()).collect::<Vec<_>>();
+        let first = delays(&bot(&["--reconnect-seed", "42"]));
+        assert_eq!(first, delays(&bot(&["--reconnect-seed", "42"])));
+        assert_ne!(first, delays(&bot(&["--reconnect-seed", "43"])));
+        let (min, max) = (RECONNECT_DELAY_SECS * (1.0 - RECONNECT_JITTER), RECONNECT_DELAY_SECS * (1.0 + RECONNECT_JITTER));
+        assert!(first.iter().all(|delay| (min..=max).contains(&delay.as_secs_f64())));
+    }
+
+    /// A self-signed certificate and its key, written as PEM files into `dir`.
+    fn write_self_signed(dir: &Path, name: &str) -> (X509, String, String) {
+        let key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = openssl::x509::X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+        let (cert_path, key_path) = (dir.join(format!("{}.crt", name)), dir.join(format!("{}.key", name)));
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert, cert_path.to_string_lossy().into_owned(), key_path.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn client_identity_is_private_and_removed_with_the_bot() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (_, cert_path, key_path) = write_self_signed(dir.path(), "client");
+        let bot = bot(&["--client-cert", &cert_path, "--client-key", &key_path]);
+        let identity_path = bot.config.client_cert_path.clone().unwrap();
+        assert_eq!(std::fs::metadata(&identity_path).unwrap().permissions().mode() & 0o777, 0o600);
+        let identity = Pkcs12::from_der(&std::fs::read(&identity_path).unwrap()).unwrap();
+        assert!(identity.parse2(bot.config.client_cert_pass.as_deref().unwrap()).is_ok());
+        drop(bot);
+        assert!(!Path::new(&identity_path).exists());
+    }
+
+    #[test]
+    fn ca_cert_becomes_the_trusted_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, cert_path, _) = write_self_signed(dir.path(), "root");
+        let bot = bot(&["--ca-cert", &cert_path]);
+        let root = std::fs::read(bot.config.cert_path.as_deref().unwrap()).unwrap();
+        assert_eq!(root, cert.to_der().unwrap());
+    }
+
+    #[test]
+    fn repeated_ca_certs_and_bundles_are_combined() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, first_path, _) = write_self_signed(dir.path(), "first");
+        let (_, second_path, _) = write_self_signed(dir.path(), "second");
+        let bundle_path = dir.path().join("bundle.crt");
+        std::fs::write(&bundle_path, [first.to_pem().unwrap(), first.to_pem().unwrap()].concat()).unwrap();
+        let bundle_path = bundle_path.to_string_lossy().into_owned();
+        let ca_args = |ca_certs: &[&str]| {
+            let mut args = vec!["rirc", "--server", "irc.example.org", "--channel", "#weather"];
+            for path in ca_certs {
+                args.extend(["--ca-cert", path]);
+            }
+            Args::try_parse_from(args).unwrap()
+        };
+
+        let bot = WeatherBot::new(ca_args(&[&first_path, &bundle_path])).unwrap();
+        let root = std::fs::read(bot.config.cert_path.as_deref().unwrap()).unwrap();
+        assert_eq!(root, first.to_der().unwrap());
+
+        let error = WeatherBot::new(ca_args(&[&first_path, &second_path])).err().unwrap();
+        assert!(error.to_string().contains("2 different certificates"), "{}", error);
+    }
+
+    #[test]
+    fn repeated_log_lines_collapse_into_a_count() {
+        let mut log = RepeatCollapser::default();
+        let error = "Connection refused. Attempting to reconnect...";
+        assert_eq!(log.record(error.to_string()), [error]);
+        for _ in 1..REPEAT_LOG_EVERY {
+            assert!(log.record(error.to_string()).is_empty());
+        }
+        assert_eq!(log.record(error.to_string()), [format!("{} (repeated {} times)", error, REPEAT_LOG_EVERY)]);
+        assert!(log.record(error.to_string()).is_empty());
+        assert_eq!(
+            log.record("Connection dropped. Reconnecting...".to_string()),
+            [format!("(previous message repeated {} times)", REPEAT_LOG_EVERY + 1), "Connection dropped. Reconnecting...".to_string()]
+        );
+    }
+
+    #[test]
+    fn channel_keys_reach_the_config() {
+        let keyed = WeatherBot::new(Args::parse_from(["rirc", "--server", "irc.example.org", "--channel", "#secret:thekey"])).unwrap();
+        assert_eq!(keyed.config.channels, ["#secret"]);
+        assert_eq!(keyed.config.channel_keys, HashMap::from([("#secret".to_string(), "thekey".to_string())]));
+
+        let open = WeatherBot::new(Args::parse_from(["rirc", "--server", "irc.example.org", "--channel", "#weather"])).unwrap();
+        assert_eq!(open.config.channels, ["#weather"]);
+        assert!(open.config.channel_keys.is_empty());
+    }
+
+    #[test]
+    fn reload_applies_changed_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rirc.json");
+        std::fs::write(&path, r#"{"admin": ["alice"], "use_notice": false}"#).unwrap();
+        let bot = bot(&["--config", path.to_str().unwrap()]);
+        assert_eq!(*bot.admins.lock().unwrap(), ["alice"]);
+
+        std::fs::write(&path, r#"{"admin": ["alice", "bob"], "use_notice": true, "server": "irc.example.net"}"#).unwrap();
+        assert_eq!(bot.reload_config(path.to_str().unwrap()).unwrap(), ["server"]);
+        assert_eq!(*bot.admins.lock().unwrap(), ["alice", "bob"]);
+        assert!(*bot.use_notice.lock().unwrap());
+
+        // A bad value leaves everything as it was.
+        std::fs::write(&path, r#"{"admin": [], "quiet_hours": "late"}"#).unwrap();
+        assert!(bot.reload_config(path.to_str().unwrap()).is_err());
+        assert_eq!(*bot.admins.lock().unwrap(), ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn welcome_marks_the_bot_registered() {
+        let bot = Arc::new(bot(&[]));
+        let client = mock_client().await;
+        bot.set_state(ConnectionState::Connecting);
+        let notice: Message = ":irc.example.org NOTICE * :*** Looking up your hostname\r\n".parse().unwrap();
+        bot.handle_message(&client, notice).await.unwrap();
+        assert_eq!(*bot.state.lock().unwrap(), ConnectionState::Connecting);
+
+        let welcome: Message = ":irc.example.org 001 rirc :Welcome to the network, rirc\r\n".parse().unwrap();
+        bot.handle_message(&client, welcome).await.unwrap();
+        assert_eq!(*bot.state.lock().unwrap(), ConnectionState::Registered);
+    }
+
+    #[tokio::test]
+    async fn server_errors_explain_the_disconnect() {
+        let bot = Arc::new(bot(&[]));
+        let client = mock_client().await;
+        assert_eq!(bot.classify_disconnect(None), Disconnect::Dropped);
+
+        let quit: Message = "ERROR :Closing Link: rirc (Quit: restarting)\r\n".parse().unwrap();
+        bot.handle_message(&client, quit).await.unwrap();
+        let last_error = bot.last_server_error.lock().unwrap().take();
+        assert_eq!(bot.classify_disconnect(last_error), Disconnect::ServerClosed("Closing Link: rirc (Quit: restarting)".to_string()));
+
+        let ban: Message = "ERROR :Closing Link: rirc (K-Lined)\r\n".parse().unwrap();
+        bot.handle_message(&client, ban).await.unwrap();
+        let last_error = bot.last_server_error.lock().unwrap().take();
+        assert_eq!(bot.classify_disconnect(last_error), Disconnect::Banned("Closing Link: rirc (K-Lined)".to_string()));
+    }
+
+    /// One of everything SavedState holds.
+    fn sample_state() -> SavedState {
+        SavedState {
+            nick_locations: HashMap::from([
+                ("alice".to_string(), SavedLocation { location: "Paris".to_string(), last_used: 1_790_000_000, units: Some(Units::Metric) }),
+                ("bob".to_string(), SavedLocation { location: "New+York".to_string(), last_used: 1_790_000_100, units: None }),
+            ]),
+            nick_labels: HashMap::from([("alice".to_string(), "Home".to_string())]),
+            nick_units: HashMap::from([("bob".to_string(), Units::Both)]),
+            nick_langs: HashMap::from([("alice".to_string(), "fr".to_string())]),
+            nick_timezones: HashMap::from([("bob".to_string(), chrono_tz::America::New_York)]),
+            private_nicks: HashSet::from(["alice".to_string()]),
+            channel_locations: HashMap::from([("#weather".to_string(), "London".to_string())]),
+            channel_langs: HashMap::from([("#meteo".to_string(), "de".to_string())]),
+            query_counts: HashMap::from([("#weather".to_string(), HashMap::from([("London".to_string(), 3), ("Paris".to_string(), 1)]))]),
+        }
+    }
+
+    #[test]
+    fn state_round_trips_through_each_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = JsonStore(dir.path().join("state.json").to_string_lossy().into_owned());
+        let sqlite = SqliteStore::open(&dir.path().join("state.db").to_string_lossy()).unwrap();
+        for store in [&json as &dyn StateStore, &sqlite] {
+            assert_eq!(store.load().unwrap(), SavedState::default());
+            store.save(&sample_state()).unwrap();
+            assert_eq!(store.load().unwrap(), sample_state());
+            // A save replaces what was there, rather than adding to it.
+            store.save(&SavedState::default()).unwrap();
+            assert_eq!(store.load().unwrap(), SavedState::default());
+        }
+    }
+
+    #[test]
+    fn ping_settings_reach_the_config() {
+        let bot = bot(&["--ping-interval", "45", "--ping-timeout", "10"]);
+        assert_eq!((bot.config.ping_time(), bot.config.ping_timeout()), (45, 10));
+        assert_eq!(
+            bot.describe_connection_error(&irc::error::Error::PingTimeout),
+            "No PONG from irc.example.org within 10s; connection presumed dead"
+        );
+
+        let args = Args::parse_from(["rirc", "--server", "irc.example.org", "--channel", "#weather", "--ping-interval", "0"]);
+        assert_eq!(args.validate(), Err("--ping-interval must be at least 1".to_string()));
+    }
+
+    #[test]
+    fn bundled_defaults_apply_until_the_state_file_exists() {
+        let bundled = r##"{"channel_locations": {"#weather": "London"}, "nick_labels": {"alice": "home"}}"##;
+        let defaults = initial_state(None, bundled).unwrap();
+        assert_eq!(defaults.channel_locations, HashMap::from([("#weather".to_string(), "London".to_string())]));
+        assert_eq!(defaults.nick_labels, HashMap::from([("alice".to_string(), "home".to_string())]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = JsonStore(dir.path().join("state.json").to_string_lossy().into_owned());
+        store.save(&sample_state()).unwrap();
+        assert_eq!(initial_state(Some(&store), bundled).unwrap(), sample_state());
+
+        // The bot itself only reads the file once it exists.
+        let path = dir.path().join("missing.json").to_string_lossy().into_owned();
+        let fresh = bot(&["--state-file", &path]);
+        assert_eq!(*fresh.nick_labels.lock().unwrap(), parse_json_state(BUNDLED_STATE).unwrap().nick_labels);
+        let restored = bot(&["--state-file", &store.0]);
+        assert_eq!(*restored.nick_labels.lock().unwrap(), sample_state().nick_labels);
+    }
+
+    #[test]
+    fn status_is_an_away_with_current_conditions() {
+        let metric = bot(&["--status-city", "London", "--units", "metric"]);
+        let bot = bot(&["--status-city", "London"]);
+        assert_eq!(
+            bot.status_command(&london(), "London").unwrap(),
+            Command::AWAY(Some("London: 70°F (feels 68°F), partly cloudy".to_string()))
+        );
+        assert_eq!(
+            metric.status_command(&london(), "London").unwrap(),
+            Command::AWAY(Some("London: 21°C (feels 20°C), partly cloudy".to_string()))
+        );
+    }
+
+    /// Counts saves instead of writing anything.
+    struct CountingStore(Arc<Mutex<usize>>);
+
+    impl StateStore for CountingStore {
+        fn load(&self) -> Result<SavedState, Box<dyn Error>> {
+            Ok(SavedState::default())
+        }
+
+        fn save(&self, _state: &SavedState) -> Result<(), Box<dyn Error>> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rapid_updates_share_one_write() {
+        let mut bot = bot(&[]);
+        let saves = Arc::new(Mutex::new(0));
+        bot.state_store = Some(Box::new(CountingStore(Arc::clone(&saves))));
+
+        // The first change is written straight away; the rest of the burst only marks it dirty.
+        for _ in 0..10 {
+            bot.persist_state();
+        }
+        assert_eq!(*saves.lock().unwrap(), 1);
+        // Inside the window a flush waits; a forced one (shutdown, reconnect) writes them.
+        bot.flush_state(false);
+        assert_eq!(*saves.lock().unwrap(), 1);
+        bot.flush_state(true);
+        assert_eq!(*saves.lock().unwrap(), 2);
+        bot.flush_state(true);
+        assert_eq!(*saves.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn prewarm_serves_requesters_in_their_own_units() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        bot.channel_locations.lock().unwrap().insert("#weather".to_string(), "London".to_string());
+        // alice's saved location already inferred metric; --units is imperial.
+        bot.nick_locations.lock().unwrap().insert(
+            "alice".to_string(),
+            SavedLocation { location: "London".to_string(), last_used: 1_790_000_000, units: Some(Units::Metric) },
+        );
+        assert_eq!(bot.prewarm_targets(), [("London".to_string(), None)]);
+        for (query, lang) in bot.prewarm_targets() {
+            bot.prewarm(&query, lang).await;
+        }
+        assert_eq!(log.urls.lock().unwrap().len(), 1);
+
+        // alice's metric reply comes from the cache, and each cached reply matches a fresh lookup.
+        let client = mock_client().await;
+        bot.handle_message(&client, ":alice!a@example.com PRIVMSG #weather :!w\r\n".parse().unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(log.urls.lock().unwrap().len(), 1);
+        assert!(bot.last_results.lock().unwrap().contains_key("alice"));
+        for units in [Units::Imperial, Units::Both] {
+            let fresh = bot.get_report("London", Report::Full { hour: None }, units, None, None).await.unwrap();
+            let cached = bot.response_cache.lock().unwrap()
+                .get(&("london".to_string(), Report::Full { hour: None }, units, None, None))
+                .map(|(response, _)| response.clone());
+            assert_eq!(cached, Some(fresh));
+        }
+    }
+}
@@ -0,0 +1,482 @@
+//! Command-line arguments and the small value types they parse into.
+
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use clap::{Parser, ValueEnum};
+use std::str::FromStr;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// IRC server address
+    #[arg(short, long, required_unless_present_any = ["emit_json", "dry_run"])]
+    pub server: Option<String>,
+
+    /// IRC server port
+    #[arg(short, long, default_value_t = 6697)]
+    pub port: u16,
+
+    /// IRC channel to join; use "#channel:key" for a keyed (+k) channel
+    #[arg(short, long, required_unless_present_any = ["emit_json", "dry_run"])]
+    pub channel: Option<String>,
+
+    /// Bot's nickname
+    #[arg(short, long, default_value = "RustWeatherBot")]
+    pub nickname: String,
+
+    /// Use TLS ("--use-tls false" for a plaintext connection)
+    #[arg(short, long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub use_tls: bool,
+
+    /// Seed for the reconnect jitter RNG (random if not set)
+    #[arg(long)]
+    pub reconnect_seed: Option<u64>,
+
+    /// Maximum number of weather fetches in flight at once
+    #[arg(long, default_value_t = 4)]
+    pub max_concurrent_fetches: usize,
+
+    /// Consecutive failed wttr.in fetches that stop further requests for --breaker-cooldown
+    /// (0 disables the breaker)
+    #[arg(long, default_value_t = 5)]
+    pub breaker_threshold: u32,
+
+    /// Seconds wttr.in is left alone once the breaker trips, before one request tests it again
+    #[arg(long, default_value_t = 60)]
+    pub breaker_cooldown: u64,
+
+    /// Hostmask allowed to run admin commands, e.g. "nick!*@host" (repeatable)
+    #[arg(long)]
+    pub admin: Vec<String>,
+
+    /// PEM client certificate for TLS client authentication
+    #[arg(long, requires = "client_key")]
+    pub client_cert: Option<String>,
+
+    /// PEM private key for the client certificate
+    #[arg(long, requires = "client_cert")]
+    pub client_key: Option<String>,
+
+    /// Accept any TLS certificate from the server (self-signed, expired, wrong host). Never use in production
+    #[arg(long)]
+    pub insecure_skip_verify: bool,
+
+    /// PEM file of extra CA certificates to trust, e.g. a private network's root (repeatable;
+    /// irc's TLS connector takes only one root, so together they must hold a single certificate)
+    #[arg(long)]
+    pub ca_cert: Vec<String>,
+
+    /// Temperature units for nicks without a saved location; a saved location's country picks
+    /// the units otherwise, and "!w units" overrides both
+    #[arg(long, value_enum, default_value_t = Units::Imperial)]
+    pub units: Units,
+
+    /// wttr.in language for condition text, e.g. de or pt-br; a channel's !wlang and a nick's
+    /// "!w lang" take precedence
+    #[arg(long, value_parser = parse_lang)]
+    pub lang: Option<String>,
+
+    /// Window during which weather commands are ignored, e.g. 23:00-07:00
+    #[arg(long)]
+    pub quiet_hours: Option<QuietHours>,
+
+    /// IANA timezone used to evaluate --quiet-hours, e.g. America/Chicago
+    #[arg(long, default_value = "UTC")]
+    pub timezone: Tz,
+
+    /// Channel the bot will answer commands in (repeatable); when set, commands anywhere else,
+    /// including private messages, are ignored
+    #[arg(long)]
+    pub allow_channel: Vec<String>,
+
+    /// Join any channel we're invited to (with --allow-channel, invites to listed channels are
+    /// always accepted and all others ignored)
+    #[arg(long)]
+    pub join_on_invite: bool,
+
+    /// Send each report as one IRCv3 draft/multiline message when the server supports it
+    #[arg(long)]
+    pub multiline: bool,
+
+    /// Only remember a nick's location when they use "!w set <city>"; "!w <city>" becomes a one-off
+    #[arg(long)]
+    pub no_auto_save: bool,
+
+    /// Channel whose pinned location is polled for severe weather alerts (repeatable)
+    #[arg(long)]
+    pub alert_channel: Vec<String>,
+
+    /// Channel to greet with a short !w introduction whenever the bot joins it (repeatable)
+    #[arg(long)]
+    pub announce_on_join: Vec<String>,
+
+    /// Seconds between the PINGs the bot sends to check the server is still there. They go out
+    /// on this fixed interval (irc's keepalive), whether or not the server has been talking
+    #[arg(long, default_value_t = 120)]
+    pub ping_interval: u32,
+
+    /// Seconds to wait for the PONG to each of those PINGs before treating the connection as dead
+    #[arg(long, default_value_t = 30)]
+    pub ping_timeout: u32,
+
+    /// Seconds a weather lookup may take before the bot gives up and says the service timed out;
+    /// also the limit on each HTTP request, including background ones
+    #[arg(long, default_value_t = 30)]
+    pub command_timeout: u64,
+
+    /// Seconds between severe weather polls
+    #[arg(long, default_value_t = 900)]
+    pub alert_interval: u64,
+
+    /// City whose current conditions the bot keeps as its away message, for anyone who WHOISes it
+    #[arg(long)]
+    pub status_city: Option<String>,
+
+    /// Seconds between refreshes of the --status-city away message
+    #[arg(long, default_value_t = 900)]
+    pub status_interval: u64,
+
+    /// Keep each channel's pinned and most looked-up locations in the response cache, so !w for
+    /// them answers without waiting on wttr.in
+    #[arg(long)]
+    pub prewarm: bool,
+
+    /// Seconds between --prewarm refreshes
+    #[arg(long, default_value_t = 300)]
+    pub prewarm_interval: u64,
+
+    /// Color a condition category, e.g. rain=12 (repeatable; conditions are uncolored by default)
+    #[arg(long, value_parser = parse_condition_color)]
+    pub condition_color: Vec<(ConditionKind, String)>,
+
+    /// Emoji for temperatures at or above a °F threshold, e.g. 86=🔥 (repeatable; replaces the
+    /// built-in 🥶/🧥/😎/🥵 table, whose lowest entry also covers anything colder)
+    #[arg(long, value_parser = parse_temp_emoji)]
+    pub temp_emoji: Vec<(i32, String)>,
+
+    /// Reply with NOTICE instead of PRIVMSG
+    #[arg(long)]
+    pub use_notice: bool,
+
+    /// Print normalized JSON weather for this location and exit instead of connecting
+    #[arg(long)]
+    pub emit_json: Option<String>,
+
+    /// Print the !w reply for this location and exit instead of connecting; IRC colors become
+    /// ANSI on a terminal and are stripped otherwise
+    #[arg(long, conflicts_with = "emit_json")]
+    pub dry_run: Option<String>,
+
+    /// Resolve place names to coordinates with Open-Meteo's geocoder before querying wttr.in
+    #[arg(long)]
+    pub geocode: bool,
+
+    /// what3words API key; with it, "!w filled.count.soap" looks up that 3m square
+    #[arg(long)]
+    pub what3words_key: Option<String>,
+
+    /// Enable "!w <city> marine" (sea temperature and waves from Open-Meteo's marine API)
+    #[arg(long)]
+    pub marine: bool,
+
+    /// Compare today's high with the 1991-2020 average high for the date (Open-Meteo's historical archive)
+    #[arg(long)]
+    pub climate_normals: bool,
+
+    /// Hour of day (0-23) whose forecast represents tomorrow and the day after; "!w <city> at 21" overrides it
+    #[arg(long, default_value_t = 12, value_parser = clap::value_parser!(u32).range(0..24))]
+    pub forecast_hour: u32,
+
+    /// Most forecast days shown by any report, however many the weather service returns
+    #[arg(long, default_value_t = 3)]
+    pub max_forecast_days: usize,
+
+    /// Strip emoji variation selectors (U+FE0F) for clients that render them as boxes
+    #[arg(long)]
+    pub strip_variation_selectors: bool,
+
+    /// Show wind direction as text (e.g. NW) instead of a compass arrow
+    #[arg(long)]
+    pub no_wind_arrows: bool,
+
+    /// Dew point (°F) below which the current conditions are described as dry
+    #[arg(long, default_value_t = 50)]
+    pub dry_dewpoint: i32,
+
+    /// Dew point (°F) at or above which the current conditions are described as muggy
+    #[arg(long, default_value_t = 65)]
+    pub muggy_dewpoint: i32,
+
+    /// Feels-like temperature (°F) below which !wear says to bring a jacket
+    #[arg(long, default_value_t = 55)]
+    pub jacket_below: i32,
+
+    /// Feels-like temperature (°F) at or above which !wear calls it shorts weather
+    #[arg(long, default_value_t = 75)]
+    pub shorts_above: i32,
+
+    /// Wind speed (mph) at or above which !wear suggests something windproof
+    #[arg(long, default_value_t = 20)]
+    pub windy_mph: i32,
+
+    /// Chance of rain (%) over the rest of the day at or above which !wear suggests an umbrella
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(0..=100))]
+    pub umbrella_chance: u32,
+
+    /// Base temperature (°F) for the heating/cooling degree days reported by "!w <city> hdd"
+    #[arg(long, default_value_t = 65)]
+    pub degree_day_base: i32,
+
+    /// File that saved locations, labels, privacy flags and pins are persisted to
+    #[arg(long)]
+    pub state_file: Option<String>,
+
+    /// Storage format for --state-file
+    #[arg(long, value_enum, default_value_t = StateBackend::Json)]
+    pub state_backend: StateBackend,
+
+    /// MaxMind GeoIP2/GeoLite2 City database; a bare !w from a nick with no saved location
+    /// (and no channel pin) guesses their location from their host's IP
+    #[arg(long)]
+    pub geoip_db: Option<String>,
+
+    /// JSON file of hot-reloadable settings (admin, condition_color, quiet_hours, temp_emoji, use_notice),
+    /// re-read by "!wadmin reload"; its values override the matching flags
+    #[arg(long)]
+    pub config: Option<String>,
+}
+
+impl Args {
+    /// Checks the constraints between flags that clap can't express, returning the first one
+    /// broken. Run before anything connects or touches the state file.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.use_tls {
+            let tls_only = [
+                ("--client-cert", self.client_cert.is_some()),
+                ("--ca-cert", !self.ca_cert.is_empty()),
+                ("--insecure-skip-verify", self.insecure_skip_verify),
+            ];
+            if let Some((flag, _)) = tls_only.iter().find(|(_, set)| *set) {
+                return Err(format!("{} needs TLS, but --use-tls is false", flag));
+            }
+        }
+        if self.insecure_skip_verify && !self.ca_cert.is_empty() {
+            return Err("--ca-cert has no effect with --insecure-skip-verify, which trusts every certificate".to_string());
+        }
+        if self.quiet_hours.is_some_and(|quiet| quiet.start == quiet.end) {
+            return Err("--quiet-hours starts and ends at the same time, so it would never apply".to_string());
+        }
+        for (flag, value) in [
+            ("--max-concurrent-fetches", self.max_concurrent_fetches as u64),
+            ("--max-forecast-days", self.max_forecast_days as u64),
+            ("--command-timeout", self.command_timeout),
+            ("--alert-interval", self.alert_interval),
+            ("--status-interval", self.status_interval),
+            ("--prewarm-interval", self.prewarm_interval),
+            ("--ping-interval", self.ping_interval as u64),
+            ("--ping-timeout", self.ping_timeout as u64),
+        ] {
+            if value == 0 {
+                return Err(format!("{} must be at least 1", flag));
+            }
+        }
+        if self.dry_dewpoint >= self.muggy_dewpoint {
+            return Err(format!(
+                "--dry-dewpoint ({}) must be below --muggy-dewpoint ({})",
+                self.dry_dewpoint, self.muggy_dewpoint
+            ));
+        }
+        if self.jacket_below > self.shorts_above {
+            return Err(format!(
+                "--jacket-below ({}) can't be above --shorts-above ({})",
+                self.jacket_below, self.shorts_above
+            ));
+        }
+        for (flag, channels) in [
+            ("--alert-channel", &self.alert_channel),
+            ("--announce-on-join", &self.announce_on_join),
+            ("--allow-channel", &self.allow_channel),
+        ] {
+            // The server's CHANTYPES isn't known yet, so accept any of the RFC 2811 prefixes.
+            if let Some(channel) = channels.iter().find(|c| !c.starts_with(['#', '&', '+', '!'])) {
+                return Err(format!("{} {} isn't a channel name; channels start with #, &, + or !", flag, channel));
+            }
+        }
+        if self.status_city.as_deref().is_some_and(|city| city.trim().is_empty()) {
+            return Err("--status-city is empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConditionKind {
+    Sunny,
+    PartlyCloudy,
+    Cloudy,
+    Fog,
+    Rain,
+    Thunder,
+    ThunderSnow,
+    Snow,
+    HeavySnow,
+    Unknown,
+}
+
+pub fn parse_condition_color(s: &str) -> Result<(ConditionKind, String), String> {
+    let (kind, color) = s.split_once('=').ok_or_else(|| format!("expected <condition>=<color>, got {}", s))?;
+    let kind = ConditionKind::from_str(kind.trim(), true)?;
+    let color = color.trim().parse::<u8>().ok().filter(|c| *c <= 99)
+        .ok_or_else(|| format!("invalid IRC color {}, expected 0-99", color))?;
+    Ok((kind, format!("{:02}", color)))
+}
+
+pub fn parse_temp_emoji(s: &str) -> Result<(i32, String), String> {
+    let (threshold, emoji) = s.split_once('=').ok_or_else(|| format!("expected <temp>=<emoji>, got {}", s))?;
+    let threshold = threshold.trim().parse::<i32>().map_err(|e| format!("invalid temperature {}: {}", threshold, e))?;
+    let emoji = emoji.trim();
+    if emoji.is_empty() {
+        return Err(format!("no emoji given for {}", threshold));
+    }
+    Ok((threshold, emoji.to_string()))
+}
+
+/// A wttr.in language code such as "de" or "pt-br", lowercased.
+pub fn parse_lang(s: &str) -> Result<String, String> {
+    let lang = s.trim().to_lowercase();
+    let parts: Vec<&str> = lang.split('-').collect();
+    let valid = parts.len() <= 2
+        && parts.iter().all(|part| (2..=4).contains(&part.len()) && part.chars().all(|c| c.is_ascii_lowercase()));
+    if valid {
+        Ok(lang)
+    } else {
+        Err(format!("invalid language code {}, expected e.g. de or pt-br", s))
+    }
+}
+
+/// Sorts a --temp-emoji table by threshold so lookups can scan it in order.
+pub fn temp_emoji_table(mut table: Vec<(i32, String)>) -> Vec<(i32, String)> {
+    table.sort_by_key(|(threshold, _)| *threshold);
+    table
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Windows where start is after end wrap past midnight.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| format!("expected HH:MM-HH:MM, got {}", s))?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| format!("invalid time {}: {}", t, e));
+        Ok(QuietHours { start: parse(start)?, end: parse(end)? })
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Units {
+    Imperial,
+    Metric,
+    /// Fahrenheit and Celsius side by side, e.g. 72°F/22°C
+    Both,
+}
+
+impl Units {
+    /// The --units spelling, as used by "!w units" and in the state file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Units::Imperial => "imperial",
+            Units::Metric => "metric",
+            Units::Both => "both",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StateBackend {
+    Json,
+    Sqlite,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let window: QuietHours = "13:00-15:30".parse().unwrap();
+        assert!(window.contains(at("13:00")));
+        assert!(window.contains(at("15:29")));
+        assert!(!window.contains(at("15:30")));
+        assert!(!window.contains(at("12:59")));
+    }
+
+    #[test]
+    fn quiet_hours_wrapping_midnight() {
+        let window: QuietHours = "23:00-07:00".parse().unwrap();
+        assert!(window.contains(at("23:00")));
+        assert!(window.contains(at("00:00")));
+        assert!(window.contains(at("06:59")));
+        assert!(!window.contains(at("07:00")));
+        assert!(!window.contains(at("12:00")));
+        assert!(!window.contains(at("22:59")));
+    }
+
+    fn args(extra: &[&str]) -> Args {
+        let argv = ["rircbot", "--server", "irc.example.org", "--channel", "#weather"];
+        Args::try_parse_from(argv.iter().chain(extra)).unwrap()
+    }
+
+    #[test]
+    fn defaults_pass_validation() {
+        let args = args(&[]);
+        assert_eq!(args.port, 6697);
+        assert!(args.use_tls);
+        assert_eq!(args.validate(), Ok(()));
+    }
+
+    #[test]
+    fn tls_only_flags_need_tls() {
+        let args = args(&["--use-tls", "false", "--ca-cert", "ca.pem"]);
+        assert_eq!(args.validate(), Err("--ca-cert needs TLS, but --use-tls is false".to_string()));
+    }
+
+    #[test]
+    fn invalid_combinations_are_explained() {
+        let cases: [(&[&str], &str); 5] = [
+            (&["--use-tls", "false", "--client-cert", "c.pem", "--client-key", "k.pem"], "--client-cert needs TLS, but --use-tls is false"),
+            (&["--insecure-skip-verify", "--ca-cert", "ca.pem"], "--ca-cert has no effect with --insecure-skip-verify, which trusts every certificate"),
+            (&["--quiet-hours", "22:00-22:00"], "--quiet-hours starts and ends at the same time, so it would never apply"),
+            (&["--dry-dewpoint", "20", "--muggy-dewpoint", "10"], "--dry-dewpoint (20) must be below --muggy-dewpoint (10)"),
+            (&["--alert-channel", "weather"], "--alert-channel weather isn't a channel name; channels start with #, &, + or !"),
+        ];
+        for (flags, error) in cases {
+            assert_eq!(args(flags).validate(), Err(error.to_string()), "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn malformed_quiet_hours_are_rejected_by_the_parser() {
+        let argv = ["rircbot", "--server", "irc.example.org", "--channel", "#weather", "--quiet-hours", "late"];
+        let error = Args::try_parse_from(argv).unwrap_err().to_string();
+        assert!(error.contains("expected HH:MM-HH:MM, got late"), "{}", error);
+    }
+}
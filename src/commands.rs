@@ -0,0 +1,174 @@
+//! Commands dispatched through the registry. Each is a self-contained struct that recognises
+//! its own trigger; the rest are still matched in `WeatherBot::handle_message`.
+
+use futures_util::future::BoxFuture;
+use irc::client::prelude::*;
+use regex::Regex;
+use std::error::Error;
+use std::sync::Arc;
+use crate::bot::WeatherBot;
+use crate::cli::Units;
+
+/// Where a command came from, for `BotCommand::execute`.
+pub struct CommandContext<'a> {
+    pub client: &'a Client,
+    pub channel: &'a str,
+}
+
+pub trait BotCommand: Send + Sync {
+    /// Whether this command handles `content` (the message text, CTCP ACTION unwrapped).
+    fn matches(&self, content: &str) -> bool;
+    fn execute<'a>(&'a self, bot: &'a Arc<WeatherBot>, context: CommandContext<'a>, content: &'a str) -> BoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// Every registered command; the first whose `matches` accepts a message handles it.
+pub fn registry() -> Vec<Box<dyn BotCommand>> {
+    vec![Box::new(TopCommand), Box::new(SourceCommand), Box::new(ConvertCommand)]
+}
+
+/// "!wtop": the channel's most looked-up locations.
+struct TopCommand;
+
+impl BotCommand for TopCommand {
+    fn matches(&self, content: &str) -> bool {
+        content.trim() == "!wtop"
+    }
+
+    fn execute<'a>(&'a self, bot: &'a Arc<WeatherBot>, context: CommandContext<'a>, _content: &'a str) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move { bot.send_chunked(&context.client.sender(), context.channel, &bot.format_top(context.channel)) })
+    }
+}
+
+/// "!w source": names the services behind the replies.
+struct SourceCommand;
+
+impl SourceCommand {
+    /// wttr.in always, and every other service an enabled option calls. Open-Meteo's geocoder
+    /// is always listed: short plus codes are resolved through it even without --geocode.
+    fn format_source(&self, bot: &WeatherBot) -> String {
+        let mut sources = vec!["wttr.in (https://wttr.in)".to_string()];
+        let geocoding = if bot.geocode { "geocoding" } else { "plus code geocoding" };
+        let open_meteo: Vec<&str> = [(true, geocoding), (bot.climate_normals, "climate normals"), (bot.marine, "marine")].into_iter()
+            .filter_map(|(enabled, purpose)| enabled.then_some(purpose))
+            .collect();
+        let open_meteo = match open_meteo.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => String::new(),
+        };
+        sources.push(format!("Open-Meteo for {} (https://open-meteo.com)", open_meteo));
+        if bot.what3words_key.is_some() {
+            sources.push("what3words (https://what3words.com)".to_string());
+        }
+        if bot.geoip.is_some() {
+            sources.push("MaxMind GeoIP for guessing locations (https://www.maxmind.com)".to_string());
+        }
+        format!("Weather data: {}", sources.join("; "))
+    }
+}
+
+impl BotCommand for SourceCommand {
+    fn matches(&self, content: &str) -> bool {
+        content.trim() == "!w source"
+    }
+
+    fn execute<'a>(&'a self, bot: &'a Arc<WeatherBot>, context: CommandContext<'a>, _content: &'a str) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move { bot.send_reply(&context.client.sender(), context.channel, &self.format_source(bot)) })
+    }
+}
+
+/// "!convert 30C" / "!convert 86F": converts one temperature, no lookup involved.
+struct ConvertCommand;
+
+impl ConvertCommand {
+    /// "30°C = 86°F"; a bare number is read in --units (°F unless that is metric).
+    fn format_conversion(&self, bot: &WeatherBot, content: &str) -> Option<String> {
+        let re_temp = Regex::new(r"^!convert\s+(-?\d{1,6}(?:\.\d{1,2})?)\s*°?\s*([CcFf])?$").unwrap();
+        let caps = re_temp.captures(content.trim())?;
+        let value = caps[1].parse::<f64>().ok()?;
+        let celsius = match caps.get(2).map(|unit| unit.as_str().to_ascii_uppercase()) {
+            Some(unit) => unit == "C",
+            None => bot.units == Units::Metric,
+        };
+        // Rounded to a tenth first so values like -0.04 don't come out as "-0".
+        let number = |n: f64| format!("{:.1}", (n * 10.0).round() / 10.0 + 0.0).trim_end_matches(".0").to_string();
+        Some(if celsius {
+            format!("{}°C = {}°F", number(value), number(value * 9.0 / 5.0 + 32.0))
+        } else {
+            format!("{}°F = {}°C", number(value), number((value - 32.0) * 5.0 / 9.0))
+        })
+    }
+}
+
+impl BotCommand for ConvertCommand {
+    fn matches(&self, content: &str) -> bool {
+        let content = content.trim();
+        content == "!convert" || content.starts_with("!convert ")
+    }
+
+    fn execute<'a>(&'a self, bot: &'a Arc<WeatherBot>, context: CommandContext<'a>, content: &'a str) -> BoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            let reply = self.format_conversion(bot, content)
+                .unwrap_or_else(|| "Usage: !convert <temperature>[C|F], e.g. !convert 30C".to_string());
+            bot.send_reply(&context.client.sender(), context.channel, &reply)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weather::testing::*;
+
+    #[test]
+    fn source_names_the_active_providers() {
+        assert_eq!(
+            SourceCommand.format_source(&bot(&[])),
+            "Weather data: wttr.in (https://wttr.in); Open-Meteo for plus code geocoding (https://open-meteo.com)"
+        );
+        assert_eq!(
+            SourceCommand.format_source(&bot(&["--geocode", "--marine"])),
+            "Weather data: wttr.in (https://wttr.in); Open-Meteo for geocoding and marine (https://open-meteo.com)"
+        );
+        let geoip_db = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/geoip_city.mmdb");
+        let everything = bot(&["--geocode", "--climate-normals", "--marine", "--what3words-key", "KEY", "--geoip-db", geoip_db]);
+        assert_eq!(
+            SourceCommand.format_source(&everything),
+            "Weather data: wttr.in (https://wttr.in); Open-Meteo for geocoding, climate normals and marine (https://open-meteo.com); \
+             what3words (https://what3words.com); MaxMind GeoIP for guessing locations (https://www.maxmind.com)"
+        );
+    }
+
+    #[test]
+    fn registry_routes_to_the_matching_command() {
+        let commands = registry();
+        // Index into registry(): TopCommand, SourceCommand, ConvertCommand.
+        let route = |content: &str| commands.iter().position(|command| command.matches(content));
+        assert_eq!(route("!wtop"), Some(0));
+        assert_eq!(route("!w source "), Some(1));
+        assert_eq!(route("!convert 30C"), Some(2));
+        assert_eq!(route("!convert"), Some(2));
+        assert_eq!(route("!converter"), None);
+        assert_eq!(route("!w London"), None);
+        assert_eq!(route("!wtop please"), None);
+    }
+
+    #[test]
+    fn convert_both_directions() {
+        let metric = bot(&["--units", "metric"]);
+        let bot = bot(&[]);
+        assert_eq!(ConvertCommand.format_conversion(&bot, "!convert 30C").as_deref(), Some("30°C = 86°F"));
+        assert_eq!(ConvertCommand.format_conversion(&bot, "!convert 86 f").as_deref(), Some("86°F = 30°C"));
+        assert_eq!(ConvertCommand.format_conversion(&bot, "!convert -40°C").as_deref(), Some("-40°C = -40°F"));
+        assert_eq!(ConvertCommand.format_conversion(&bot, "!convert 50").as_deref(), Some("50°F = 10°C"));
+        assert_eq!(ConvertCommand.format_conversion(&metric, "!convert 10").as_deref(), Some("10°C = 50°F"));
+    }
+
+    #[test]
+    fn convert_rejects_invalid_input() {
+        let bot = bot(&[]);
+        for content in ["!convert", "!convert hot", "!convert 30K", "!convert 1e9", "!convert 1234567C"] {
+            assert_eq!(ConvertCommand.format_conversion(&bot, content), None, "{}", content);
+        }
+    }
+}
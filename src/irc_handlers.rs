@@ -0,0 +1,1644 @@
+//! Message dispatch: turning PRIVMSG, JOIN, INVITE and CAP traffic into commands and replies.
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use irc::client::data::AccessLevel;
+use irc::client::prelude::*;
+use irc::proto::message::Tag;
+use irc::proto::{BatchSubCommand, CapSubCommand};
+use rand::distributions::Alphanumeric;
+use regex::Regex;
+use std::error::Error;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+use rand::Rng;
+use crate::bot::{ConnectionState, SavedLocation, WeatherBot};
+use crate::cli::{parse_lang, Units};
+use crate::commands::CommandContext;
+use crate::weather::Report;
+
+/// What the server allows in a draft/multiline batch, from its CAP LS value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MultilineLimits {
+    /// Total bytes of text across the batch's lines
+    max_bytes: usize,
+    max_lines: Option<usize>,
+}
+
+/// What a "!w" message asks for, as read by `WeatherBot::parse_weather_query`.
+#[derive(Clone, Debug, PartialEq)]
+enum WeatherQuery {
+    /// Bare "!w": the sender's saved location, else the channel's pinned one
+    Saved,
+    /// A location to fetch, saved as the sender's when `save`. A single word is also kept in
+    /// `nick`, since "!w alice" means alice's location if she has one saved.
+    Location { query: String, save: bool, nick: Option<String> },
+    /// Another nick's saved location
+    Nick(String),
+}
+
+/// A nick's most recent weather reply, re-sent by "!w again".
+pub struct LastResult {
+    query: String,
+    report: Report,
+    reply: String,
+    at: Instant,
+}
+
+const DEFAULT_CHUNK_BYTES: usize = 400;
+/// Room for ":nick!user@host" as the server relays our messages (nick 30, user 10, host 63).
+const PREFIX_OVERHEAD: usize = 106;
+
+/// Channel prefixes assumed until the server's ISUPPORT says otherwise.
+const DEFAULT_CHANTYPES: &str = "#&";
+
+const MAX_WHO_NICKS: usize = 20;
+const TOP_LOCATIONS: usize = 5;
+
+/// How long a formatted reply stays fresh: "!w again" re-sends it and identical requests
+/// reuse it; after that the data is fetched again.
+const LAST_RESULT_TTL_SECS: u64 = 600;
+
+/// Sent to --announce-on-join channels when the bot joins them.
+const JOIN_GREETING: &str = "Hi! I'm a weather bot. Try !w <city> for the forecast, or just !w once I know your location.";
+
+impl WeatherBot {
+    /// Handles one message from the read loop. A failed reply shouldn't drop the connection;
+    /// if the connection is really gone, the stream ends on its own.
+    pub async fn process_message(self: &Arc<Self>, client: &Client, message: Message) {
+        if let Err(e) = self.handle_message(client, message).await {
+            eprintln!("Error handling message: {}", e);
+        }
+    }
+
+    /// Weather lookups run on their own task so a slow wttr.in response can't stall the read
+    /// loop; irc answers server PINGs while the stream is polled, so a blocked loop used to
+    /// get the bot timed out.
+    pub async fn handle_message(self: &Arc<Self>, client: &Client, message: Message) -> Result<(), Box<dyn Error>> {
+        if let Command::Response(Response::RPL_WELCOME, _) = message.command {
+            self.set_state(ConnectionState::Registered);
+        }
+        if let Command::ERROR(ref reason) = message.command {
+            *self.last_server_error.lock().unwrap() = Some(reason.clone());
+        }
+        if let Command::Response(Response::RPL_ISUPPORT, ref params) = message.command {
+            if let Some(line_len) = self.parse_line_len(params) {
+                *self.line_len.lock().unwrap() = Some(line_len);
+            }
+            if let Some(chantypes) = self.parse_chantypes(params) {
+                *self.chantypes.lock().unwrap() = Some(chantypes);
+            }
+        }
+        if let Command::CAP(_, ref subcommand, ref field, ref last) = message.command {
+            // The capability list is the final parameter; a multi-line LS puts "*" before it.
+            let caps = last.as_deref().or(field.as_deref()).unwrap_or_default();
+            match subcommand {
+                CapSubCommand::LS if self.multiline => {
+                    if let Some(limits) = self.parse_multiline_cap(caps) {
+                        *self.multiline_offer.lock().unwrap() = Some(limits);
+                        client.send(Command::CAP(None, CapSubCommand::REQ, None, Some("batch draft/multiline".to_string())))?;
+                    }
+                }
+                CapSubCommand::ACK if caps.split_whitespace().any(|cap| cap == "draft/multiline") => {
+                    let offer = *self.multiline_offer.lock().unwrap();
+                    *self.multiline_limits.lock().unwrap() = offer;
+                }
+                _ => {}
+            }
+        }
+        for channel in self.channels_to_greet(&message, client.current_nickname()) {
+            self.send_reply(&client.sender(), channel, JOIN_GREETING)?;
+        }
+        if let Command::INVITE(_, ref channel) = message.command {
+            if self.should_accept_invite(channel) {
+                client.send_join(channel)?;
+            }
+        }
+        if let Command::PRIVMSG(target, content) = message.command {
+            if !self.is_allowed_channel(&target) {
+                return Ok(());
+            }
+            if let Some(Prefix::Nickname(nick, user, host)) = message.prefix {
+                // Replies to a private message go back to the sender, not to our own nick.
+                let channel = if self.is_channel(&target) { target } else { nick.clone() };
+                let content = self.command_text(self.first_line(&content));
+                let mention = self.mention_command(content, client.current_nickname());
+                let content = mention.as_deref().unwrap_or(content);
+                if let Some(command) = self.commands.iter().find(|command| command.matches(content)) {
+                    command.execute(self, CommandContext { client, channel: &channel }, content).await?;
+                } else if let Some(location) = self.parse_pin_command(content) {
+                    if self.is_channel_op(client, &channel, &nick) || self.is_admin(&nick, &user, &host) {
+                        self.send_reply(&client.sender(), &channel, &format!("Pinned {} as the default location for {}", location, channel))?;
+                        self.channel_locations.lock().unwrap().insert(channel, location);
+                        self.persist_state();
+                    } else {
+                        self.send_reply(&client.sender(), &channel, "Only channel operators can pin a location.")?;
+                    }
+                } else if let Some(lang) = self.parse_lang_command(content, "!wlang") {
+                    if self.is_channel_op(client, &channel, &nick) || self.is_admin(&nick, &user, &host) {
+                        let reply = match lang {
+                            Some(lang) => {
+                                let reply = format!("Weather in {} now uses the {} language", channel, lang);
+                                self.channel_langs.lock().unwrap().insert(channel.clone(), lang);
+                                reply
+                            }
+                            None => {
+                                self.channel_langs.lock().unwrap().remove(&channel);
+                                format!("Weather in {} now uses the default language", channel)
+                            }
+                        };
+                        self.persist_state();
+                        self.send_reply(&client.sender(), &channel, &reply)?;
+                    } else {
+                        self.send_reply(&client.sender(), &channel, "Only channel operators can set the channel's language.")?;
+                    }
+                } else if self.parse_reload_command(content) {
+                    let reply = if !self.is_admin(&nick, &user, &host) {
+                        "Only bot admins can reload the configuration.".to_string()
+                    } else if let Some(path) = &self.config_path {
+                        match self.reload_config(path) {
+                            Ok(restart_keys) if restart_keys.is_empty() => format!("Reloaded {}", path),
+                            Ok(restart_keys) => format!("Reloaded {}; {} need a restart and were not applied", path, restart_keys.join(", ")),
+                            Err(e) => format!("Could not reload {}: {}", path, e),
+                        }
+                    } else {
+                        "No --config file to reload.".to_string()
+                    };
+                    self.send_reply(&client.sender(), &channel, &reply)?;
+                } else if let Some(days) = self.parse_prune_command(content) {
+                    let reply = if self.is_admin(&nick, &user, &host) {
+                        format!("Pruned {} saved locations unused for {} days", self.prune_locations(days), days)
+                    } else {
+                        "Only bot admins can prune saved locations.".to_string()
+                    };
+                    self.send_reply(&client.sender(), &channel, &reply)?;
+                } else if let Some(label) = self.parse_label_command(content) {
+                    if self.nick_locations.lock().unwrap().contains_key(&nick) {
+                        self.send_reply(&client.sender(), &channel, &format!("{}: your saved location is now labelled {}", nick, label))?;
+                        self.nick_labels.lock().unwrap().insert(nick, label);
+                        self.persist_state();
+                    } else {
+                        self.send_reply(&client.sender(), &channel, &format!("{}: set a location with !w <city> before labelling it", nick))?;
+                    }
+                } else if let Some(private) = self.parse_private_command(content) {
+                    let reply = if private {
+                        self.private_nicks.lock().unwrap().insert(nick.clone());
+                        "your saved location is now private"
+                    } else {
+                        self.private_nicks.lock().unwrap().remove(&nick);
+                        "your saved location is now visible to others"
+                    };
+                    self.persist_state();
+                    self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, reply))?;
+                } else if let Some(units) = self.parse_units_command(content) {
+                    let reply = match units {
+                        Some(units) => {
+                            self.nick_units.lock().unwrap().insert(nick.clone(), units);
+                            format!("your weather now uses {} units", units.name())
+                        }
+                        None => {
+                            self.nick_units.lock().unwrap().remove(&nick);
+                            "your weather now uses your saved location's units".to_string()
+                        }
+                    };
+                    self.persist_state();
+                    self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, reply))?;
+                } else if let Some(lang) = self.parse_lang_command(content, "!w lang") {
+                    let reply = match lang {
+                        Some(lang) => {
+                            let reply = format!("your weather now uses the {} language", lang);
+                            self.nick_langs.lock().unwrap().insert(nick.clone(), lang);
+                            reply
+                        }
+                        None => {
+                            self.nick_langs.lock().unwrap().remove(&nick);
+                            "your weather now uses the channel's language".to_string()
+                        }
+                    };
+                    self.persist_state();
+                    self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, reply))?;
+                } else if let Some(timezone) = self.parse_tz_command(content) {
+                    let reply = match timezone {
+                        Some(timezone) => {
+                            self.nick_timezones.lock().unwrap().insert(nick.clone(), timezone);
+                            format!("times in your weather are now shown in {}", timezone.name())
+                        }
+                        None => {
+                            self.nick_timezones.lock().unwrap().remove(&nick);
+                            "times in your weather are now the location's local time".to_string()
+                        }
+                    };
+                    self.persist_state();
+                    self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, reply))?;
+                } else if let Some(location) = self.parse_who_command(content) {
+                    let reply = self.format_who(&nick, &location);
+                    self.send_chunked(&client.sender(), &channel, &reply)?;
+                } else if self.is_quiet_hours() {
+                    // Weather commands are ignored during quiet hours.
+                } else if self.parse_again_command(content) {
+                    let last = self.last_results.lock().unwrap().get(&nick)
+                        .map(|last| (last.query.clone(), last.report, last.reply.clone(), last.at.elapsed()));
+                    match last {
+                        Some((_, _, reply, age)) if age < Duration::from_secs(LAST_RESULT_TTL_SECS) => {
+                            self.send_report(&client.sender(), &channel, &reply)?;
+                        }
+                        Some((query, report, _, _)) => {
+                            let bot = Arc::clone(self);
+                            let sender = client.sender();
+                            tokio::spawn(async move {
+                                bot.send_weather_data(&sender, &channel, &nick, &query, report).await;
+                            });
+                        }
+                        None => self.send_reply(&client.sender(), &channel, &format!("{}: nothing to repeat yet, try !w <city>", nick))?,
+                    }
+                } else if self.parse_whereami_command(content) {
+                    // A one-off lookup; unlike a bare !w it never saves the guess.
+                    match self.whereami_query(&nick, &host) {
+                        Ok(query) => {
+                            let bot = Arc::clone(self);
+                            let sender = client.sender();
+                            tokio::spawn(async move {
+                                bot.send_weather_data(&sender, &channel, &nick, &query, Report::Full { hour: None }).await;
+                            });
+                        }
+                        Err(reply) => self.send_reply(&client.sender(), &channel, &reply)?,
+                    }
+                } else if let Some((target, command)) = self.parse_relay_command(content) {
+                    let joined = client.list_channels().unwrap_or_default();
+                    if let Some(refusal) = self.relay_refusal(&joined, &nick, self.is_admin(&nick, &user, &host), &target) {
+                        self.send_reply(&client.sender(), &channel, &refusal)?;
+                    } else {
+                        let (command, report) = self.split_report(&command);
+                        match self.resolve_weather_query(&command, &nick, &target) {
+                            Ok(Some(query)) => {
+                                let bot = Arc::clone(self);
+                                let sender = client.sender();
+                                tokio::spawn(async move {
+                                    bot.send_weather_data(&sender, &target, &nick, &query, report).await;
+                                });
+                            }
+                            Ok(None) => {}
+                            Err(refusal) => self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, refusal))?,
+                        }
+                    }
+                } else if let Some(other) = self.parse_diff_command(content) {
+                    let home = self.nick_locations.lock().unwrap().get(&nick).map(|saved| saved.location.clone());
+                    match home {
+                        Some(home) => {
+                            let bot = Arc::clone(self);
+                            let sender = client.sender();
+                            tokio::spawn(async move {
+                                if let Err(e) = bot.send_weather_diff(&sender, &channel, &nick, &home, &other).await {
+                                    eprintln!("Error sending weather diff to {}: {}", channel, e);
+                                }
+                            });
+                        }
+                        None => self.send_reply(&client.sender(), &channel, &format!("{}: set a location with !w <city> first so there's something to compare against", nick))?,
+                    }
+                } else {
+                    let (command, report) = self.split_report(content);
+                    // CSV rows are for logs, not for reading along in a channel.
+                    if report == Report::Csv && self.is_channel(&channel) && !self.is_admin(&nick, &user, &host) {
+                        self.send_reply(&client.sender(), &channel, &format!("{}: ask for csv in a private message", nick))?;
+                        return Ok(());
+                    }
+                    let query = match self.resolve_weather_query(&command, &nick, &channel) {
+                        Ok(None) if command == "!w" => Ok(self.geoip_location(&host)),
+                        query => query,
+                    };
+                    match query {
+                        Ok(Some(query)) => {
+                            let bot = Arc::clone(self);
+                            let sender = client.sender();
+                            tokio::spawn(async move {
+                                // Only fresh lookups in a channel feed !wtop and prewarming;
+                                // PMs, repeats and relays would skew them.
+                                if bot.send_weather_data(&sender, &channel, &nick, &query, report).await && bot.is_channel(&channel) {
+                                    bot.count_query(&channel, &query);
+                                }
+                            });
+                        }
+                        Ok(None) => {}
+                        Err(refusal) => self.send_reply(&client.sender(), &channel, &format!("{}: {}", nick, refusal))?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Content should never carry CR/LF, but if a malformed message does, only the first line
+    /// is parsed so nothing after a line break can be echoed back as a raw IRC line.
+    fn first_line<'a>(&self, content: &'a str) -> &'a str {
+        content.split(['\r', '\n']).next().unwrap_or_default()
+    }
+
+    /// For a CTCP ACTION like "/me checks !w London", returns the text from the command onwards;
+    /// plain messages are returned unchanged.
+    fn command_text<'a>(&self, content: &'a str) -> &'a str {
+        match content.strip_prefix("\x01ACTION ") {
+            Some(action) => {
+                let action = action.strip_suffix('\x01').unwrap_or(action);
+                action.find("!w").map_or(action, |start| &action[start..])
+            }
+            None => content,
+        }
+    }
+
+    /// Turns "WeatherBot: London" or "WeatherBot, weather in London?" into "!w London". Only a
+    /// message that opens with our nick and a ':' or ',' counts, and the rest must read like a
+    /// short place name, so chatting with the bot doesn't trigger lookups.
+    fn mention_command(&self, content: &str, own_nick: &str) -> Option<String> {
+        let re_mention = Regex::new(r"(?i)^(\S+?)[:,]\s+(?:weather(?:\s+(?:in|for|at))?\s+)?(.+)$").unwrap();
+        let re_place = Regex::new(r"^[\p{L}\d][\p{L}\d\s,.'-]*$").unwrap();
+        let caps = re_mention.captures(content.trim())?;
+        // A trailing '?' is fine ("weather in London?"); "thanks!" isn't a place.
+        let place = caps[2].trim_end().trim_end_matches('?');
+        let addressed = caps[1].eq_ignore_ascii_case(own_nick);
+        (addressed && re_place.is_match(place) && place.split_whitespace().count() <= 4)
+            .then(|| format!("!w {}", place))
+    }
+
+    /// True when there's no --allow-channel list or the channel is on it.
+    fn is_allowed_channel(&self, channel: &str) -> bool {
+        self.allow_channels.is_empty() || self.allow_channels.iter().any(|allowed| allowed.eq_ignore_ascii_case(channel))
+    }
+
+    /// The --announce-on-join channels a JOIN from `own_nick` just put the bot in. The server
+    /// echoes our own JOIN once per join; other nicks joining are ignored.
+    fn channels_to_greet<'m>(&self, message: &'m Message, own_nick: &str) -> Vec<&'m str> {
+        let Command::JOIN(ref channels, _, _) = message.command else {
+            return Vec::new();
+        };
+        let own_join = matches!(&message.prefix, Some(Prefix::Nickname(nick, _, _)) if nick == own_nick);
+        channels.split(',').filter(|channel| own_join && self.should_announce(channel)).collect()
+    }
+
+    fn should_announce(&self, channel: &str) -> bool {
+        self.announce_channels.iter().any(|announce| announce.eq_ignore_ascii_case(channel))
+    }
+
+    fn should_accept_invite(&self, channel: &str) -> bool {
+        if self.allow_channels.is_empty() {
+            self.join_on_invite
+        } else {
+            self.is_allowed_channel(channel)
+        }
+    }
+
+    fn is_quiet_hours(&self) -> bool {
+        let now = Utc::now().with_timezone(&self.timezone).time();
+        self.quiet_hours.lock().unwrap().is_some_and(|window| window.contains(now))
+    }
+
+    fn is_channel_op(&self, client: &Client, channel: &str, nick: &str) -> bool {
+        client.list_users(channel).is_some_and(|users| {
+            users.iter().any(|u| u.get_nickname() == nick && u.highest_access_level() >= AccessLevel::Oper)
+        })
+    }
+
+    fn is_admin(&self, nick: &str, user: &str, host: &str) -> bool {
+        let mask = format!("{}!{}@{}", nick, user, host);
+        self.admins.lock().unwrap().iter().any(|pattern| {
+            let re = format!("^{}$", regex::escape(pattern).replace(r"\*", ".*"));
+            Regex::new(&re).is_ok_and(|re| re.is_match(&mask))
+        })
+    }
+
+    fn parse_pin_command(&self, content: &str) -> Option<String> {
+        let re_pin = Regex::new(r"^!wpin (.+)").unwrap();
+        re_pin.captures(content).map(|caps| self.normalize_location(&caps[1]))
+    }
+
+    /// "<command> de" -> Some(Some("de")); "<command> off" -> Some(None), clearing the choice.
+    /// Used for both "!w lang" and !wlang.
+    fn parse_lang_command(&self, content: &str, command: &str) -> Option<Option<String>> {
+        let setting = content.trim().strip_prefix(command)?.strip_prefix(' ')?.trim();
+        if setting.eq_ignore_ascii_case("off") {
+            return Some(None);
+        }
+        parse_lang(setting).ok().map(Some)
+    }
+
+    /// "!w tz America/New_York" -> Some(Some(tz)); "!w tz off" -> Some(None), going back to
+    /// the location's local time.
+    fn parse_tz_command(&self, content: &str) -> Option<Option<Tz>> {
+        let setting = content.trim().strip_prefix("!w tz ")?.trim();
+        if setting.eq_ignore_ascii_case("off") {
+            return Some(None);
+        }
+        setting.parse::<Tz>().ok().map(Some)
+    }
+
+    fn parse_label_command(&self, content: &str) -> Option<String> {
+        let re_label = Regex::new(r"^!w label (.+)").unwrap();
+        re_label.captures(content).map(|caps| caps[1].trim().to_string()).filter(|label| !label.is_empty())
+    }
+
+    fn save_location(&self, nick: &str, query: &str) {
+        let mut nick_locations = self.nick_locations.lock().unwrap();
+        // Units inferred for the same location still hold; a new location is inferred afresh.
+        let units = nick_locations.get(nick).filter(|previous| previous.location == query).and_then(|previous| previous.units);
+        let saved = SavedLocation { location: query.to_string(), last_used: Utc::now().timestamp(), units };
+        let previous = nick_locations.insert(nick.to_string(), saved);
+        drop(nick_locations);
+        if previous.is_none_or(|previous| previous.location != query) {
+            self.nick_labels.lock().unwrap().remove(nick);
+            self.persist_state();
+        }
+    }
+
+    /// "nick's weather", or "nick's Home weather" when the query is their labelled saved location.
+    fn response_prefix(&self, nick: &str, query: &str) -> String {
+        let is_saved = self.nick_locations.lock().unwrap().get(nick).is_some_and(|saved| saved.location == query);
+        match self.nick_labels.lock().unwrap().get(nick) {
+            Some(label) if is_saved => format!("{}'s {} weather", nick, label),
+            _ => format!("{}'s weather", nick),
+        }
+    }
+
+    /// "!wto #chan London" -> ("#chan", "!w London"), for posting a report into another channel.
+    /// The target must be a channel by the server's CHANTYPES.
+    fn parse_relay_command(&self, content: &str) -> Option<(String, String)> {
+        let re_relay = Regex::new(r"^!wto (\S+)(.*)$").unwrap();
+        re_relay.captures(content.trim())
+            .filter(|caps| self.is_channel(&caps[1]))
+            .map(|caps| (caps[1].to_string(), format!("!w{}", &caps[2])))
+    }
+
+    /// Why "!wto" can't post into `target`, if it can't: only admins may relay, and only into
+    /// channels the bot has joined.
+    fn relay_refusal(&self, joined: &[String], nick: &str, is_admin: bool, target: &str) -> Option<String> {
+        if !is_admin {
+            Some("Only bot admins can send weather to another channel.".to_string())
+        } else if !joined.iter().any(|c| c.eq_ignore_ascii_case(target)) {
+            Some(format!("{}: I'm not in {}", nick, target))
+        } else {
+            None
+        }
+    }
+
+    fn parse_diff_command(&self, content: &str) -> Option<String> {
+        let re_diff = Regex::new(r"^!w diff (.+)").unwrap();
+        re_diff.captures(content.trim()).map(|caps| self.normalize_location(&caps[1]))
+    }
+
+    fn parse_who_command(&self, content: &str) -> Option<String> {
+        let re_who = Regex::new(r"^!wwho (.+)").unwrap();
+        re_who.captures(content).map(|caps| self.normalize_location(&caps[1]))
+    }
+
+    /// Counts a successful lookup; "paris" and "Paris" share the first spelling seen.
+    fn count_query(&self, channel: &str, query: &str) {
+        {
+            let mut query_counts = self.query_counts.lock().unwrap();
+            let counts = query_counts.entry(channel.to_string()).or_default();
+            let key = counts.keys().find(|location| location.eq_ignore_ascii_case(query)).cloned()
+                .unwrap_or_else(|| query.to_string());
+            *counts.entry(key).or_default() += 1;
+        }
+        self.persist_state();
+    }
+
+    /// The channel's most looked-up locations, most popular first (ties alphabetical).
+    pub fn format_top(&self, channel: &str) -> String {
+        let top = self.top_locations(channel, TOP_LOCATIONS);
+        if top.is_empty() {
+            return format!("No weather lookups in {} yet.", channel);
+        }
+        let top: Vec<String> = top.iter()
+            .map(|(location, count)| format!("{} ({})", location.replace('+', " "), count))
+            .collect();
+        format!("Top locations in {}: {}", channel, top.join(", "))
+    }
+
+    /// The channel's `limit` most looked-up locations with their counts, most popular first
+    /// (ties alphabetical).
+    pub fn top_locations(&self, channel: &str, limit: usize) -> Vec<(String, u32)> {
+        let mut counts: Vec<(String, u32)> = self.query_counts.lock().unwrap().get(channel)
+            .map(|counts| counts.iter().map(|(location, count)| (location.clone(), *count)).collect())
+            .unwrap_or_default();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(limit);
+        counts
+    }
+
+    /// Nicks who set "!w private on" are left out, except from their own lookups.
+    fn format_who(&self, requester: &str, location: &str) -> String {
+        let private_nicks = self.private_nicks.lock().unwrap();
+        let mut nicks: Vec<String> = self.nick_locations.lock().unwrap().iter()
+            .filter(|(nick, _)| nick.as_str() == requester || !private_nicks.contains(nick.as_str()))
+            .filter(|(_, saved)| saved.location.eq_ignore_ascii_case(location))
+            .map(|(nick, _)| nick.clone())
+            .collect();
+        if nicks.is_empty() {
+            return format!("Nobody has {} saved.", location.replace('+', " "));
+        }
+        nicks.sort();
+        let total = nicks.len();
+        nicks.truncate(MAX_WHO_NICKS);
+        let mut reply = format!("{} saved by: {}", location.replace('+', " "), nicks.join(", "));
+        if total > MAX_WHO_NICKS {
+            reply.push_str(&format!(" and {} more", total - MAX_WHO_NICKS));
+        }
+        reply
+    }
+
+    /// Splits a trailing report keyword such as "trend", or a day offset such as "2", off a command.
+    /// "!wx <city>", "!rain <city>", "!snow <city>", "!precip <city>" and "!t <city>" are
+    /// rewritten to "!w <city>" with the detailed, rain, snow, precipitation and temperature reports.
+    fn split_report(&self, content: &str) -> (String, Report) {
+        let content = content.trim();
+        if let Some(rest) = content.strip_prefix("!wx").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Detailed);
+        }
+        if let Some(rest) = content.strip_prefix("!rain").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Rain);
+        }
+        if let Some(rest) = content.strip_prefix("!snow").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Snow);
+        }
+        if let Some(rest) = content.strip_prefix("!precip").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Precip);
+        }
+        if let Some(rest) = content.strip_prefix("!wear").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Wear);
+        }
+        if let Some(rest) = content.strip_prefix("!t").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            return (format!("!w{}", rest), Report::Temp);
+        }
+        match content.rsplit_once(' ') {
+            Some((command, "trend")) => (command.to_string(), Report::Trend),
+            Some((command, "short")) => (command.to_string(), Report::Short),
+            Some((command, "hdd")) => (command.to_string(), Report::DegreeDays),
+            Some((command, "marine")) => (command.to_string(), Report::Marine),
+            Some((command, "temp")) => (command.to_string(), Report::Temp),
+            Some((command, "table")) => (command.to_string(), Report::Table),
+            Some((command, "ranges")) => (command.to_string(), Report::Ranges),
+            Some((command, "extremes")) => (command.to_string(), Report::Extremes),
+            Some((command, "csv")) => (command.to_string(), Report::Csv),
+            _ => {
+                let re_at = Regex::new(r"^(.*) at (\d{1,2})$").unwrap();
+                let re_day = Regex::new(r"^(!w\S*(?: .*)?) (\d{1,2})$").unwrap();
+                if let Some(caps) = re_at.captures(content).filter(|caps| caps[2].parse::<u32>().is_ok_and(|h| h < 24)) {
+                    (caps[1].to_string(), Report::Full { hour: caps[2].parse().ok() })
+                } else if let Some(caps) = re_day.captures(content) {
+                    (caps[1].to_string(), Report::Day { offset: caps[2].parse().unwrap_or_default() })
+                } else {
+                    (content.to_string(), Report::Full { hour: None })
+                }
+            }
+        }
+    }
+
+    /// With --geoip-db, guesses "lat,lon" for a host that is a bare IP address. Cloaks, vhosts
+    /// and hostnames yield None, as do addresses the database doesn't place.
+    fn geoip_location(&self, host: &str) -> Option<String> {
+        let reader = self.geoip.as_ref()?;
+        let ip = host.parse::<IpAddr>().ok()?;
+        let city = match reader.lookup(ip).and_then(|result| result.decode::<maxminddb::geoip2::City>()) {
+            Ok(city) => city?,
+            Err(e) => {
+                eprintln!("GeoIP lookup for {} failed: {}", ip, e);
+                return None;
+            }
+        };
+        Some(format!("{},{}", city.location.latitude?, city.location.longitude?))
+    }
+
+    /// Reads a "!w" message without touching saved state; None unless it starts with the
+    /// command. `resolve_weather_query` then applies it against the saved locations.
+    fn parse_weather_query(&self, content: &str) -> Option<WeatherQuery> {
+        let re_saved_nick = Regex::new(r"^!w (\S+)$").unwrap();
+        let re_landmark = Regex::new(r"^!w (~\S.*)$").unwrap();
+        let re_airport = Regex::new(r"^!w ([A-Z]{3})$").unwrap();
+        let re_location = Regex::new(r"^!w ([a-zA-Z,\s]+)").unwrap();
+        let re_zip = Regex::new(r"^!w (\d+)").unwrap();
+        let re_nick = Regex::new(r"^!w ([^\d\s]+)").unwrap();
+
+        // Some clients pad messages with spaces; "  !w London  " should behave like "!w London".
+        let content = content.trim();
+        // "!w? <city>" is a one-off lookup that leaves the nick's saved location alone, and
+        // "!w set <city>" always saves; a plain "!w <city>" saves unless --no-auto-save is set.
+        let (content, save) = if let Some(rest) = content.strip_prefix("!w? ") {
+            (format!("!w {}", rest.trim_start()), false)
+        } else if let Some(rest) = content.strip_prefix("!w set ") {
+            (format!("!w {}", rest.trim_start()), true)
+        } else {
+            (content.to_string(), self.auto_save)
+        };
+        let content = content.as_str();
+        if content == "!w" {
+            return Some(WeatherQuery::Saved);
+        }
+        // Checked first: the patterns below would cut these short, reading "8FVC9G8F+6X" as
+        // zip 8 and "filled.count.soap" as "filled".
+        if let Some(address) = content.strip_prefix("!w ").map(|rest| self.normalize_location(rest)).filter(|rest| self.is_address(rest)) {
+            return Some(WeatherQuery::Location { query: address, save, nick: None });
+        }
+        let nick = re_saved_nick.captures(content).map(|caps| self.trim_location(&caps[1]).to_string());
+
+        let query = if let Some(caps) = re_landmark.captures(content) {
+            self.trim_location(&caps[1]).replace(" ", "+")
+        } else if let Some(caps) = re_airport.captures(content) {
+            caps[1].to_string()
+        } else if let Some(caps) = re_location.captures(content) {
+            self.normalize_location(&caps[1])
+        } else if let Some(caps) = re_zip.captures(content) {
+            format!("{},+USA", &caps[1])
+        } else {
+            return re_nick.captures(content).map(|caps| WeatherQuery::Nick(self.trim_location(&caps[1]).to_string()));
+        };
+        Some(WeatherQuery::Location { query, save, nick })
+    }
+
+    /// Turns a "!w" message into the query to fetch, saving or looking up locations as it asks.
+    /// An Err carries a refusal to send back (e.g. a private nick).
+    fn resolve_weather_query(&self, content: &str, nick: &str, channel: &str) -> Result<Option<String>, String> {
+        match self.parse_weather_query(content) {
+            None => Ok(None),
+            Some(WeatherQuery::Saved) => {
+                let saved = self.nick_locations.lock().unwrap().get_mut(nick).map(|saved| {
+                    saved.last_used = Utc::now().timestamp();
+                    saved.location.clone()
+                });
+                Ok(saved.or_else(|| self.channel_locations.lock().unwrap().get(channel).cloned()))
+            }
+            Some(WeatherQuery::Nick(target)) => self.lookup_nick(nick, &target),
+            Some(WeatherQuery::Location { nick: Some(target), .. }) if self.nick_locations.lock().unwrap().contains_key(&target) => {
+                self.lookup_nick(nick, &target)
+            }
+            Some(WeatherQuery::Location { query, save, .. }) => {
+                if save {
+                    self.save_location(nick, &query);
+                }
+                Ok(Some(query))
+            }
+        }
+    }
+
+    fn lookup_nick(&self, requester: &str, target_nick: &str) -> Result<Option<String>, String> {
+        if target_nick != requester && self.private_nicks.lock().unwrap().contains(target_nick) {
+            return Err(format!("{}'s location is private", target_nick));
+        }
+        Ok(self.nick_locations.lock().unwrap().get(target_nick).map(|saved| saved.location.clone()))
+    }
+
+    fn parse_private_command(&self, content: &str) -> Option<bool> {
+        match content.trim() {
+            "!w private on" => Some(true),
+            "!w private off" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// "!w units metric" -> Some(Some(Metric)); "!w units auto" -> Some(None), going back to
+    /// the saved location's units.
+    fn parse_units_command(&self, content: &str) -> Option<Option<Units>> {
+        let setting = content.trim().strip_prefix("!w units ")?.trim();
+        if setting.eq_ignore_ascii_case("auto") {
+            return Some(None);
+        }
+        Units::from_str(setting, true).ok().map(Some)
+    }
+
+    fn parse_reload_command(&self, content: &str) -> bool {
+        content.trim() == "!wadmin reload"
+    }
+
+    fn parse_prune_command(&self, content: &str) -> Option<u32> {
+        let re_prune = Regex::new(r"^!wadmin prune (\d+)$").unwrap();
+        re_prune.captures(content.trim()).and_then(|caps| caps[1].parse().ok())
+    }
+
+    /// Forgets the saved location (and its label and privacy setting) of every nick that hasn't
+    /// used it within `days`; returns how many were removed.
+    fn prune_locations(&self, days: u32) -> usize {
+        let cutoff = Utc::now().timestamp() - i64::from(days) * 86_400;
+        let mut stale = Vec::new();
+        self.nick_locations.lock().unwrap().retain(|nick, saved| {
+            let keep = saved.last_used >= cutoff;
+            if !keep {
+                stale.push(nick.clone());
+            }
+            keep
+        });
+        if !stale.is_empty() {
+            for nick in &stale {
+                self.nick_labels.lock().unwrap().remove(nick);
+                self.private_nicks.lock().unwrap().remove(nick);
+            }
+            self.persist_state();
+        }
+        stale.len()
+    }
+
+    fn parse_again_command(&self, content: &str) -> bool {
+        content.trim() == "!w again"
+    }
+
+    fn parse_whereami_command(&self, content: &str) -> bool {
+        content.trim() == "!whereami"
+    }
+
+    /// What "!whereami" looks up for a host: the GeoIP guess, or the reply asking for a city
+    /// when there's no --geoip-db or the host can't be placed (a cloak, say).
+    fn whereami_query(&self, nick: &str, host: &str) -> Result<String, String> {
+        if self.geoip.is_none() {
+            return Err(format!("{}: I can't look up locations from hosts here; try !w <city>", nick));
+        }
+        self.geoip_location(host)
+            .ok_or_else(|| format!("{}: I couldn't place your host; try !w <city>", nick))
+    }
+
+    /// "Portland, OR" -> "Portland+OR": runs of spaces and commas collapse to a single '+'.
+    pub fn normalize_location(&self, location: &str) -> String {
+        self.trim_location(location)
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Strips trailing sentence punctuation and whitespace, e.g. "London?" or "Paris. ".
+    /// Digits are left alone so postal codes and coordinates pass through intact.
+    fn trim_location<'a>(&self, location: &'a str) -> &'a str {
+        location.trim_end_matches(|c: char| c.is_whitespace() || matches!(c, '?' | '.' | '!' | ',' | ';' | ':'))
+    }
+
+    /// Looks up and sends a report, returning whether it went out. Runs in its own task, so a
+    /// failed send (say the connection dropped mid-lookup) is only logged; the message loop
+    /// notices the disconnect itself.
+    async fn send_weather_data(&self, sender: &Sender, channel: &str, nick: &str, query: &str, report: Report) -> bool {
+        let units = self.units_for(nick).await;
+        let lang = self.lang_for(nick, channel);
+        let home = self.nick_timezones.lock().unwrap().get(nick).copied();
+        let response = match self.cached_response(query, report, units, lang.as_deref(), home) {
+            Some(response) => Ok(response),
+            None => {
+                let response = self.with_timeout(query, self.get_report(query, report, units, lang.as_deref(), home)).await;
+                if let Ok(response) = &response {
+                    self.response_cache.lock().unwrap()
+                        .insert((query.to_lowercase(), report, units, lang, home), (response.clone(), Instant::now()));
+                }
+                response
+            }
+        };
+        match response {
+            Ok(response) => {
+                let mut response = response;
+                if let Report::Full { .. } = report {
+                    if let Some(change) = self.reading_change(nick, query, units) {
+                        response = format!("{} {}", response, change);
+                    }
+                }
+                // CSV is left bare so each line parses as a row.
+                let full_response = match report {
+                    Report::Csv => response,
+                    _ => format!("{}: {}", self.response_prefix(nick, query), response),
+                };
+                if let Err(e) = self.send_report(sender, channel, &full_response) {
+                    eprintln!("Error sending weather to {}: {}", channel, e);
+                    return false;
+                }
+                self.last_results.lock().unwrap().insert(nick.to_string(), LastResult {
+                    query: query.to_string(),
+                    report,
+                    reply: full_response,
+                    at: Instant::now(),
+                });
+                true
+            }
+            Err(e) => {
+                if let Err(send_error) = self.send_reply(sender, channel, &format!("Error: Could not get weather data for {}. {}", query, e)) {
+                    eprintln!("Error sending weather to {}: {}", channel, send_error);
+                }
+                false
+            }
+        }
+    }
+
+    /// How the query's current temperature compares with what the nick was last shown for it,
+    /// recording this reading for next time. None the first time, or after they've looked
+    /// somewhere else in between.
+    fn reading_change(&self, nick: &str, query: &str, units: Units) -> Option<String> {
+        let now = self.current_readings.lock().unwrap().get(&query.to_lowercase()).copied()?;
+        let previous = self.nick_readings.lock().unwrap().insert(nick.to_string(), (query.to_lowercase(), now));
+        let (location, was) = previous?;
+        (location == query.to_lowercase()).then(|| self.format_reading_change(was, now, units))
+    }
+
+    /// A reply formatted within the last LAST_RESULT_TTL_SECS for the same query, report, units,
+    /// language and home timezone; stale entries are dropped on the way.
+    fn cached_response(&self, query: &str, report: Report, units: Units, lang: Option<&str>, home: Option<Tz>) -> Option<String> {
+        let mut cache = self.response_cache.lock().unwrap();
+        cache.retain(|_, (_, at)| at.elapsed() < Duration::from_secs(LAST_RESULT_TTL_SECS));
+        cache.get(&(query.to_lowercase(), report, units, lang.map(str::to_string), home)).map(|(response, _)| response.clone())
+    }
+
+    /// The condition-text language for a query: the nick's "!w lang", else the channel's
+    /// !wlang, else --lang.
+    fn lang_for(&self, nick: &str, channel: &str) -> Option<String> {
+        let nick_lang = self.nick_langs.lock().unwrap().get(nick).cloned();
+        nick_lang
+            .or_else(|| self.channel_langs.lock().unwrap().get(channel).cloned())
+            .or_else(|| self.lang.clone())
+    }
+
+    /// The units for a nick's replies: their "!w units" choice, else their saved location's
+    /// country's, else --units. A newly saved location costs one extra lookup to find its
+    /// country; the result is kept with the location, and when no country could be found
+    /// --units is kept instead so the lookup isn't repeated on every "!w".
+    async fn units_for(&self, nick: &str) -> Units {
+        if let Some(units) = self.nick_units.lock().unwrap().get(nick).copied() {
+            return units;
+        }
+        let saved = self.nick_locations.lock().unwrap().get(nick).map(|saved| (saved.location.clone(), saved.units));
+        let location = match saved {
+            Some((_, Some(units))) => return units,
+            Some((location, None)) => location,
+            None => return self.units,
+        };
+        let inferred = match self.with_timeout(&location, self.get_weather(&location, None)).await {
+            Ok(data) => self.infer_units(&data),
+            Err(e) => {
+                eprintln!("Error inferring units for {}: {}", location, e);
+                None
+            }
+        };
+        let units = inferred.unwrap_or(self.units);
+        if let Some(saved) = self.nick_locations.lock().unwrap().get_mut(nick).filter(|saved| saved.location == location) {
+            saved.units = Some(units);
+        }
+        self.persist_state();
+        units
+    }
+
+    /// Gives up on a lookup after --command-timeout so a hung wttr.in request can't keep its
+    /// task, and whatever it was about to reply, waiting forever.
+    async fn with_timeout<T>(&self, query: &str, lookup: impl Future<Output = Result<T, Box<dyn Error>>>) -> Result<T, Box<dyn Error>> {
+        tokio::time::timeout(self.command_timeout, lookup).await.unwrap_or_else(|_| {
+            eprintln!("Weather lookup for {} timed out after {}s", query, self.command_timeout.as_secs());
+            Err("the weather service timed out".into())
+        })
+    }
+
+    async fn send_weather_diff(&self, sender: &Sender, channel: &str, nick: &str, home: &str, other: &str) -> Result<(), Box<dyn Error>> {
+        let units = self.units_for(nick).await;
+        let lang = self.lang_for(nick, channel);
+        // Errors are stringified so neither result holds a non-Send error while the other is awaited.
+        let (home_data, other_data) = tokio::join!(
+            async { self.with_timeout(home, self.get_weather(home, lang.as_deref())).await.map_err(|e| e.to_string()) },
+            async { self.with_timeout(other, self.get_weather(other, lang.as_deref())).await.map_err(|e| e.to_string()) },
+        );
+        let reply = match (home_data, other_data) {
+            (Ok(home_data), Ok(other_data)) => format!("{}: {}", nick, self.format_diff(&home_data, home, &other_data, other, units)),
+            (Err(e), _) => format!("Error: Could not get weather data for {}. {}", home, e),
+            (_, Err(e)) => format!("Error: Could not get weather data for {}. {}", other, e),
+        };
+        self.send_chunked(sender, channel, &reply)
+    }
+
+    pub fn send_chunked(&self, sender: &Sender, channel: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        for chunk in self.chunk_message(message, self.chunk_size(channel)) {
+            self.send_reply(sender, channel, &chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a report as one draft/multiline batch with a line per section ("Tomorrow: ...")
+    /// when the server has enabled it and the report fits its limits; otherwise chunked. A
+    /// newline in the report (as in a table) always starts a new line or message.
+    fn send_report(&self, sender: &Sender, channel: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        let limits = *self.multiline_limits.lock().unwrap();
+        let Some(lines) = limits.and_then(|limits| self.multiline_lines(message, self.chunk_size(channel), limits)) else {
+            for line in message.lines() {
+                self.send_chunked(sender, channel, line)?;
+            }
+            return Ok(());
+        };
+
+        let batch: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect();
+        sender.send(Command::BATCH(
+            format!("+{}", batch),
+            Some(BatchSubCommand::CUSTOM("draft/multiline".to_string())),
+            Some(vec![channel.to_string()]),
+        ))?;
+        for (line, concat) in lines {
+            let mut tags = vec![Tag("batch".to_string(), Some(batch.clone()))];
+            if concat {
+                tags.push(Tag("draft/multiline-concat".to_string(), None));
+            }
+            sender.send(Message { tags: Some(tags), prefix: None, command: self.reply_command(channel, line) })?;
+        }
+        sender.send(Command::BATCH(format!("-{}", batch), None, None))?;
+        Ok(())
+    }
+
+    /// A report's sections as batch lines, each chunked to `chunk_size` with the continuation
+    /// chunks flagged to be joined onto the line before; None if that exceeds `limits`, or if
+    /// there is nothing to send.
+    fn multiline_lines(&self, message: &str, chunk_size: usize, limits: MultilineLimits) -> Option<Vec<(String, bool)>> {
+        let lines: Vec<(String, bool)> = message.lines()
+            .flat_map(|line| line.split(" | "))
+            .flat_map(|section| {
+                self.chunk_message(section, chunk_size).into_iter()
+                    .enumerate()
+                    .map(|(i, chunk)| (chunk, i > 0))
+            })
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        // Separate lines are joined with a newline when the batch is reassembled.
+        let bytes: usize = lines.iter().map(|(line, concat)| line.len() + usize::from(!concat)).sum::<usize>() - 1;
+        let fits = bytes <= limits.max_bytes && limits.max_lines.is_none_or(|max_lines| lines.len() <= max_lines);
+        fits.then_some(lines)
+    }
+
+    /// Finds "draft/multiline=max-bytes=4096,max-lines=24" in a CAP LS list. The spec requires
+    /// max-bytes, so an offer without it is ignored.
+    fn parse_multiline_cap(&self, caps: &str) -> Option<MultilineLimits> {
+        let value = caps.split_whitespace().find_map(|cap| cap.strip_prefix("draft/multiline="))?;
+        let limit = |key: &str| value.split(',')
+            .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+            .and_then(|n| n.parse().ok());
+        Some(MultilineLimits { max_bytes: limit("max-bytes")?, max_lines: limit("max-lines") })
+    }
+
+    /// "CHANTYPES=#&" -> "#&". An empty value is kept: the network has no channels.
+    fn parse_chantypes(&self, params: &[String]) -> Option<String> {
+        params.iter().find_map(|param| param.strip_prefix("CHANTYPES=")).map(str::to_string)
+    }
+
+    /// Whether a message target is a channel rather than a nick, going by the server's
+    /// CHANTYPES, or the usual # and & if it didn't advertise them.
+    fn is_channel(&self, target: &str) -> bool {
+        let chantypes = self.chantypes.lock().unwrap();
+        let prefixes = chantypes.as_deref().unwrap_or(DEFAULT_CHANTYPES);
+        target.chars().next().is_some_and(|first| prefixes.contains(first))
+    }
+
+    fn parse_line_len(&self, params: &[String]) -> Option<usize> {
+        params.iter()
+            .find_map(|param| param.strip_prefix("LINELEN="))
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Payload bytes per line: the advertised LINELEN minus our relayed prefix, the command and
+    /// the target, or DEFAULT_CHUNK_BYTES when the server didn't advertise one.
+    fn chunk_size(&self, channel: &str) -> usize {
+        match *self.line_len.lock().unwrap() {
+            Some(line_len) => {
+                let overhead = PREFIX_OVERHEAD + " PRIVMSG ".len() + channel.len() + " :".len() + "\r\n".len();
+                line_len.saturating_sub(overhead).max(1)
+            }
+            None => DEFAULT_CHUNK_BYTES,
+        }
+    }
+
+    /// Splits between grapheme clusters so no chunk exceeds `max_bytes` of UTF-8 (a single
+    /// cluster longer than that still gets a chunk of its own).
+    fn chunk_message(&self, message: &str, max_bytes: usize) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut current = String::new();
+        // Emoji sequences (ZWJ, variation selectors) are one cluster, so they're never split.
+        for grapheme in message.graphemes(true) {
+            if !current.is_empty() && current.len() + grapheme.len() > max_bytes {
+                chunks.push(std::mem::take(&mut current));
+            }
+            current.push_str(grapheme);
+        }
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+        chunks
+    }
+
+    pub fn send_reply(&self, sender: &Sender, channel: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        sender.send(self.reply_command(channel, message.to_string()))?;
+        Ok(())
+    }
+
+    /// A NOTICE with --use-notice, otherwise a PRIVMSG.
+    fn reply_command(&self, channel: &str, message: String) -> Command {
+        if *self.use_notice.lock().unwrap() {
+            Command::NOTICE(channel.to_string(), message)
+        } else {
+            Command::PRIVMSG(channel.to_string(), message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weather::testing::*;
+
+    #[test]
+    fn trailing_punctuation_is_trimmed() {
+        let bot = bot(&[]);
+        for content in ["!w London?", "!w London.", "!w London!", "!w London?! "] {
+            assert_eq!(
+                bot.parse_weather_query(content),
+                Some(WeatherQuery::Location { query: "London".to_string(), save: true, nick: Some("London".to_string()) }),
+                "{}", content
+            );
+        }
+        assert_eq!(bot.trim_location("New York?"), "New York");
+        assert_eq!(bot.trim_location("90210."), "90210");
+    }
+
+    #[tokio::test]
+    async fn a_hung_fetch_does_not_stall_the_message_loop() {
+        let fetcher = CannedFetcher::london().delay(Duration::from_secs(30));
+        let (bot, log) = bot_with(&[], fetcher);
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let message: Message = ":alice!a@example.com PRIVMSG #weather :!w London\r\n".parse().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), bot.handle_message(&client, message)).await
+            .expect("handle_message waited on the fetch").unwrap();
+        // The next line off the wire, a PING here, is handled while the fetch is still pending.
+        let ping: Message = "PING :irc.example.org\r\n".parse().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), bot.handle_message(&client, ping)).await
+            .expect("handle_message waited on the fetch").unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=j1"]);
+    }
+
+    #[tokio::test]
+    async fn landmarks_and_airport_codes_reach_wttr_unchanged() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        for (content, url) in [
+            ("!w ~Eiffel+Tower", "https://wttr.in/~Eiffel+Tower?format=j1"),
+            ("!w ~Eiffel Tower", "https://wttr.in/~Eiffel+Tower?format=j1"),
+            ("!w SFO", "https://wttr.in/SFO?format=j1"),
+        ] {
+            let Some(WeatherQuery::Location { query, .. }) = bot.parse_weather_query(content) else {
+                panic!("{} wasn't a location query", content);
+            };
+            log.urls.lock().unwrap().clear();
+            bot.get_weather(&query, None).await.unwrap();
+            assert_eq!(*log.urls.lock().unwrap(), [url], "{}", content);
+        }
+    }
+
+    #[test]
+    fn wwho_lists_every_nick_with_the_location() {
+        let bot = bot(&[]);
+        bot.save_location("carol", "Paris");
+        bot.save_location("alice", "Paris");
+        bot.save_location("bob", "paris");
+        bot.save_location("dave", "Berlin");
+        let location = bot.parse_who_command("!wwho Paris").unwrap();
+        assert_eq!(bot.format_who("erin", &location), "Paris saved by: alice, bob, carol");
+        assert_eq!(bot.format_who("erin", "Rome"), "Nobody has Rome saved.");
+    }
+
+    #[test]
+    fn labels_name_the_saved_location_until_it_changes() {
+        let bot = bot(&[]);
+        bot.save_location("alice", "lax+airport");
+        let label = bot.parse_label_command("!w label Home").unwrap();
+        bot.nick_labels.lock().unwrap().insert("alice".to_string(), label);
+        assert_eq!(bot.response_prefix("alice", "lax+airport"), "alice's Home weather");
+        // The label belongs to the saved location, not to one-off lookups elsewhere.
+        assert_eq!(bot.response_prefix("alice", "Paris"), "alice's weather");
+
+        bot.save_location("alice", "Paris");
+        assert_eq!(bot.response_prefix("alice", "Paris"), "alice's weather");
+        assert!(!bot.nick_labels.lock().unwrap().contains_key("alice"));
+    }
+
+    #[test]
+    fn padding_does_not_change_the_query() {
+        let bot = bot(&[]);
+        assert_eq!(bot.parse_weather_query("  !w  "), Some(WeatherQuery::Saved));
+        assert_eq!(bot.parse_weather_query("\t!w"), Some(WeatherQuery::Saved));
+        assert_eq!(bot.parse_weather_query("  !w London  "), bot.parse_weather_query("!w London"));
+        assert_eq!(bot.parse_weather_query(" !w 90210 "), bot.parse_weather_query("!w 90210"));
+    }
+
+    #[test]
+    fn use_notice_selects_the_notice_path() {
+        let reply = |bot: &WeatherBot| bot.reply_command("#weather", "London: 21°C".to_string());
+        assert_eq!(reply(&bot(&[])), Command::PRIVMSG("#weather".to_string(), "London: 21°C".to_string()));
+        assert_eq!(reply(&bot(&["--use-notice"])), Command::NOTICE("#weather".to_string(), "London: 21°C".to_string()));
+    }
+
+    #[tokio::test]
+    async fn an_action_with_a_command_triggers_a_lookup() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let message: Message = ":alice!a@example.com PRIVMSG #weather :\x01ACTION checks !w London\x01\r\n".parse().unwrap();
+        bot.handle_message(&client, message).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=j1"]);
+    }
+
+    #[test]
+    fn chunks_follow_the_advertised_line_length() {
+        let bot = bot(&[]);
+        assert_eq!(bot.chunk_size("#weather"), DEFAULT_CHUNK_BYTES);
+
+        let params: Vec<String> = ["rirc", "CHANTYPES=#", "LINELEN=512", "are supported by this server"].map(String::from).to_vec();
+        *bot.line_len.lock().unwrap() = bot.parse_line_len(&params);
+        // 512 less the relayed prefix, " PRIVMSG ", "#weather", " :" and CRLF.
+        assert_eq!(bot.chunk_size("#weather"), 512 - PREFIX_OVERHEAD - 9 - 8 - 2 - 2);
+
+        *bot.line_len.lock().unwrap() = Some(200);
+        let chunks = bot.chunk_message(&"sunny ".repeat(40), bot.chunk_size("#weather"));
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 200 - PREFIX_OVERHEAD - 21));
+        assert_eq!(chunks.concat(), "sunny ".repeat(40));
+    }
+
+    #[test]
+    fn private_locations_are_hidden_from_others() {
+        let bot = bot(&[]);
+        bot.save_location("alice", "Paris");
+        bot.save_location("bob", "Paris");
+        assert_eq!(bot.parse_private_command("!w private on"), Some(true));
+        bot.private_nicks.lock().unwrap().insert("alice".to_string());
+
+        assert_eq!(bot.lookup_nick("bob", "alice"), Err("alice's location is private".to_string()));
+        assert_eq!(bot.resolve_weather_query("!w alice", "bob", "#weather"), Err("alice's location is private".to_string()));
+        assert_eq!(bot.format_who("bob", "Paris"), "Paris saved by: bob");
+        // The owner still sees their own.
+        assert_eq!(bot.lookup_nick("alice", "alice"), Ok(Some("Paris".to_string())));
+        assert_eq!(bot.format_who("alice", "Paris"), "Paris saved by: alice, bob");
+    }
+
+    #[tokio::test]
+    async fn embedded_newlines_end_the_command() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        assert_eq!(bot.first_line("!w London\r\nQUIT :bye"), "!w London");
+        assert_eq!(bot.first_line("!w Paris\nPRIVMSG #other :hi"), "!w Paris");
+
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let message = Message {
+            tags: None,
+            prefix: Some(Prefix::Nickname("alice".to_string(), "a".to_string(), "example.com".to_string())),
+            command: Command::PRIVMSG("#weather".to_string(), "!w London\r\nQUIT :bye".to_string()),
+        };
+        bot.handle_message(&client, message).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=j1"]);
+    }
+
+    #[tokio::test]
+    async fn again_reuses_the_last_result() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let privmsg = |content: &str| format!(":alice!a@example.com PRIVMSG #weather :{}\r\n", content).parse::<Message>().unwrap();
+
+        bot.handle_message(&client, privmsg("!w London")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = bot.last_results.lock().unwrap().get("alice").map(|last| last.reply.clone()).unwrap();
+        assert!(reply.starts_with("alice's weather"), "{}", reply);
+        let fetches = log.urls.lock().unwrap().len();
+
+        bot.handle_message(&client, privmsg("!w again")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(log.urls.lock().unwrap().len(), fetches);
+    }
+
+    #[test]
+    fn relay_targets_are_parsed_and_checked() {
+        let bot = bot(&[]);
+        assert_eq!(bot.parse_relay_command("!wto #other London"), Some(("#other".to_string(), "!w London".to_string())));
+        assert_eq!(bot.parse_relay_command("!wto #other"), Some(("#other".to_string(), "!w".to_string())));
+        assert_eq!(bot.parse_relay_command("!wto London"), None);
+
+        let joined = ["#weather".to_string(), "#Other".to_string()];
+        assert_eq!(bot.relay_refusal(&joined, "alice", true, "#other"), None);
+        assert_eq!(bot.relay_refusal(&joined, "alice", true, "#elsewhere"), Some("alice: I'm not in #elsewhere".to_string()));
+        assert_eq!(
+            bot.relay_refusal(&joined, "mallory", false, "#other"),
+            Some("Only bot admins can send weather to another channel.".to_string())
+        );
+    }
+
+    #[test]
+    fn city_with_state_or_country_normalizes_cleanly() {
+        let bot = bot(&[]);
+        for (content, query) in [
+            ("!w Portland, OR", "Portland+OR"),
+            ("!w Portland,OR", "Portland+OR"),
+            ("!w Portland ,, ME", "Portland+ME"),
+            ("!w Paris, France", "Paris+France"),
+            ("!w New York, NY, USA", "New+York+NY+USA"),
+        ] {
+            let Some(WeatherQuery::Location { query: parsed, .. }) = bot.parse_weather_query(content) else {
+                panic!("{} wasn't a location query", content);
+            };
+            assert_eq!(parsed, query, "{}", content);
+        }
+    }
+
+    #[test]
+    fn top_lists_the_most_queried_cities() {
+        let bot = bot(&[]);
+        for query in ["Paris", "London", "paris", "Berlin", "London", "Paris", "Rome", "Oslo", "Madrid", "New+York", "Oslo"] {
+            bot.count_query("#weather", query);
+        }
+        bot.count_query("#other", "Tokyo");
+        assert_eq!(bot.format_top("#weather"), "Top locations in #weather: Paris (3), London (2), Oslo (2), Berlin (1), Madrid (1)");
+        assert_eq!(bot.top_locations("#other", 5), [("Tokyo".to_string(), 1)]);
+        assert_eq!(bot.format_top("#quiet"), "No weather lookups in #quiet yet.");
+    }
+
+    #[test]
+    fn geoip_guesses_from_an_ip_host_only() {
+        // A one-network database: 81.2.69.0/24 is in London.
+        let bot = bot(&["--geoip-db", concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/geoip_city.mmdb")]);
+        assert_eq!(bot.geoip_location("81.2.69.142"), Some("51.5142,-0.0931".to_string()));
+        assert_eq!(bot.geoip_location("192.0.2.1"), None);
+        assert_eq!(bot.geoip_location("user/alice"), None);
+        assert_eq!(bot.geoip_location("Rizon-4F2B1C3A.example.com"), None);
+    }
+
+    #[test]
+    fn one_off_queries_leave_the_saved_location_alone() {
+        let bot = bot(&[]);
+        assert_eq!(bot.resolve_weather_query("!w London", "alice", "#weather"), Ok(Some("London".to_string())));
+        assert_eq!(
+            bot.parse_weather_query("!w? Paris"),
+            Some(WeatherQuery::Location { query: "Paris".to_string(), save: false, nick: Some("Paris".to_string()) })
+        );
+        assert_eq!(bot.resolve_weather_query("!w? Paris", "alice", "#weather"), Ok(Some("Paris".to_string())));
+        assert_eq!(bot.nick_locations.lock().unwrap()["alice"].location, "London");
+        assert_eq!(bot.resolve_weather_query("!w", "alice", "#weather"), Ok(Some("London".to_string())));
+    }
+
+    #[test]
+    fn chunking_keeps_emoji_sequences_whole() {
+        let bot = bot(&[]);
+        let family = "\u{1F469}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let cloud = "\u{26C5}\u{FE0F}";
+        assert_eq!(bot.chunk_message(&format!("xxxxxxxxx{} sun", cloud), 10), ["xxxxxxxxx", &format!("{} sun", cloud)]);
+        assert_eq!(bot.chunk_message(&format!("aaaaaaaa{}b", family), 10), ["aaaaaaaa", family, "b"]);
+        for chunk in bot.chunk_message(&format!("{} {} ", cloud, family).repeat(20), 16) {
+            assert!(chunk.len() <= 18, "{}", chunk);
+            assert!(!chunk.starts_with(['\u{FE0F}', '\u{200D}']), "{:?}", chunk);
+            assert!(!chunk.ends_with('\u{200D}'), "{:?}", chunk);
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_outside_allowed_channels_are_ignored() {
+        let (bot, log) = bot_with(&["--allow-channel", "#weather"], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        for line in [
+            ":alice!a@example.com PRIVMSG #elsewhere :!w London\r\n",
+            ":alice!a@example.com PRIVMSG rirc :!w London\r\n",
+        ] {
+            bot.handle_message(&client, line.parse().unwrap()).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(log.urls.lock().unwrap().is_empty());
+        assert!(bot.nick_locations.lock().unwrap().is_empty());
+
+        bot.handle_message(&client, ":alice!a@example.com PRIVMSG #Weather :!w London\r\n".parse().unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!log.urls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invites_are_accepted_only_where_allowed() {
+        let allowlist = bot(&["--allow-channel", "#weather", "--allow-channel", "#meteo"]);
+        assert!(allowlist.should_accept_invite("#meteo"));
+        assert!(allowlist.should_accept_invite("#Weather"));
+        assert!(!allowlist.should_accept_invite("#random"));
+
+        let anywhere = bot(&["--join-on-invite"]);
+        assert!(anywhere.should_accept_invite("#random"));
+
+        let nowhere = bot(&[]);
+        assert!(!nowhere.should_accept_invite("#weather"));
+    }
+
+    #[test]
+    fn auto_save_can_be_turned_off() {
+        let manual = bot(&["--no-auto-save"]);
+        let bot = bot(&[]);
+        for bot in [&bot, &manual] {
+            bot.resolve_weather_query("!w set Oslo", "alice", "#weather").unwrap();
+            bot.resolve_weather_query("!w Paris", "alice", "#weather").unwrap();
+        }
+        assert_eq!(bot.nick_locations.lock().unwrap()["alice"].location, "Paris");
+        assert_eq!(manual.nick_locations.lock().unwrap()["alice"].location, "Oslo");
+
+        manual.resolve_weather_query("!w set Paris", "alice", "#weather").unwrap();
+        assert_eq!(manual.nick_locations.lock().unwrap()["alice"].location, "Paris");
+    }
+
+    #[test]
+    fn addressing_the_bot_by_nick_is_a_command() {
+        let bot = bot(&[]);
+        for (content, command) in [
+            ("rirc: London", Some("!w London")),
+            ("rirc, weather in Paris", Some("!w Paris")),
+            ("RIRC: weather for New York", Some("!w New York")),
+            ("rirc:", None),
+            ("rircbot: London", None),
+            ("I think rirc: London", None),
+        ] {
+            assert_eq!(bot.mention_command(content, "rirc").as_deref(), command, "{}", content);
+        }
+    }
+
+    #[test]
+    fn prune_removes_only_stale_entries() {
+        let bot = bot(&[]);
+        for nick in ["alice", "bob", "carol"] {
+            bot.save_location(nick, "Paris");
+        }
+        let days_ago = |days: i64| Utc::now().timestamp() - days * 86_400;
+        bot.nick_locations.lock().unwrap().get_mut("bob").unwrap().last_used = days_ago(91);
+        bot.nick_locations.lock().unwrap().get_mut("carol").unwrap().last_used = days_ago(89);
+        bot.nick_labels.lock().unwrap().insert("bob".to_string(), "Home".to_string());
+        bot.private_nicks.lock().unwrap().insert("bob".to_string());
+
+        assert_eq!(bot.parse_prune_command("!wadmin prune 90"), Some(90));
+        assert_eq!(bot.prune_locations(90), 1);
+        let mut kept: Vec<String> = bot.nick_locations.lock().unwrap().keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, ["alice", "carol"]);
+        assert!(bot.nick_labels.lock().unwrap().is_empty());
+        assert!(bot.private_nicks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn identical_requests_reuse_the_formatted_response() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        let sender = mock_client().await.sender();
+        bot.send_weather_data(&sender, "#weather", "alice", "London", Report::Temp).await;
+        assert_eq!(log.urls.lock().unwrap().len(), 1);
+        assert_eq!(
+            bot.cached_response("london", Report::Temp, Units::Imperial, None, None).as_deref(),
+            Some("London: 70°F (feels 68°F), partly cloudy")
+        );
+
+        bot.send_weather_data(&sender, "#weather", "bob", "London", Report::Temp).await;
+        assert_eq!(log.urls.lock().unwrap().len(), 1);
+        // Different units are formatted separately.
+        assert_eq!(bot.cached_response("London", Report::Temp, Units::Metric, None, None), None);
+    }
+
+    #[tokio::test]
+    async fn multiline_batches_need_the_capability() {
+        let client = mock_client().await;
+        let ls: Message = ":irc.example.org CAP * LS :batch draft/multiline=max-bytes=4096,max-lines=24 sasl\r\n".parse().unwrap();
+        let ack: Message = ":irc.example.org CAP rirc ACK :batch draft/multiline\r\n".parse().unwrap();
+
+        let off = Arc::new(bot(&[]));
+        off.handle_message(&client, ls.clone()).await.unwrap();
+        off.handle_message(&client, ack.clone()).await.unwrap();
+        assert_eq!(*off.multiline_limits.lock().unwrap(), None);
+
+        let on = Arc::new(bot(&["--multiline"]));
+        on.handle_message(&client, ls).await.unwrap();
+        assert_eq!(*on.multiline_limits.lock().unwrap(), None);
+        on.handle_message(&client, ack).await.unwrap();
+        let limits = MultilineLimits { max_bytes: 4096, max_lines: Some(24) };
+        assert_eq!(*on.multiline_limits.lock().unwrap(), Some(limits));
+
+        let lines = on.multiline_lines("London: 21°C | Tomorrow: 22°C | Day After: 20°C", 400, limits).unwrap();
+        assert_eq!(lines, [("London: 21°C".to_string(), false), ("Tomorrow: 22°C".to_string(), false), ("Day After: 20°C".to_string(), false)]);
+        assert_eq!(on.multiline_lines("", 400, limits), None);
+        assert_eq!(on.multiline_lines("London: 21°C | Tomorrow: 22°C", 400, MultilineLimits { max_bytes: 4096, max_lines: Some(1) }), None);
+    }
+
+    /// One bot shared by the property tests; building one per case dominates their run time.
+    fn shared_bot() -> &'static WeatherBot {
+        static BOT: std::sync::OnceLock<WeatherBot> = std::sync::OnceLock::new();
+        BOT.get_or_init(|| bot(&[]))
+    }
+
+    /// Whether parse_weather_query may accept the message: a bare "!w" or one of its prefixes.
+    fn is_weather_command(content: &str) -> bool {
+        let content = content.trim();
+        content == "!w" || ["!w ", "!w? ", "!w set "].iter().any(|prefix| content.starts_with(prefix))
+    }
+
+    /// Arbitrary text, or text behind the command and near misses such as "!wx", "!w?" and "say !w".
+    fn weather_messages() -> impl proptest::strategy::Strategy<Value = String> {
+        use proptest::strategy::Strategy;
+        proptest::prop_oneof![
+            "\\PC*",
+            ("( )?(!w|!w |!w\\? |!w set |!w\\?|!wx|!wtop|!wset |w |!W |x!w |say !w )", "\\PC*").prop_map(|(prefix, rest)| prefix + &rest),
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parse_weather_query_never_panics(content in weather_messages()) {
+            let parsed = shared_bot().parse_weather_query(&content);
+            proptest::prop_assert!(parsed.is_none() || is_weather_command(&content), "{:?} parsed as {:?}", content, parsed);
+        }
+
+        #[test]
+        fn parse_weather_query_handles_arbitrary_arguments(rest in "[a-zA-Z][a-zA-Z ,]{0,30}", prefix in "!w|!w\\?|!w set") {
+            let content = format!("{} {}", prefix, rest);
+            let parsed = shared_bot().parse_weather_query(&content);
+            proptest::prop_assert!(parsed.is_some(), "{:?} was not parsed", content);
+        }
+    }
+
+    #[test]
+    fn trailing_number_is_a_day_offset() {
+        let bot = bot(&[]);
+        assert_eq!(bot.split_report("!w London 2"), ("!w London".to_string(), Report::Day { offset: 2 }));
+        assert_eq!(bot.split_report("!w London at 15"), ("!w London".to_string(), Report::Full { hour: Some(15) }));
+    }
+
+    #[test]
+    fn greeting_only_on_own_join() {
+        let bot = bot(&["--announce-on-join", "#weather"]);
+        let join = |nick: &str, channels: &str| Message::new(Some(&format!("{}!user@host", nick)), "JOIN", vec![channels]).unwrap();
+        assert_eq!(bot.channels_to_greet(&join("rirc", "#weather"), "rirc"), ["#weather"]);
+        assert_eq!(bot.channels_to_greet(&join("rirc", "#other,#Weather"), "rirc"), ["#Weather"]);
+        assert!(bot.channels_to_greet(&join("alice", "#weather"), "rirc").is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_lookup_times_out() {
+        let slow = CannedFetcher::london().delay(Duration::from_secs(3));
+        let (bot, _) = bot_with(&["--command-timeout", "1"], slow);
+        let lookup = bot.with_timeout("London", bot.get_report("London", Report::Temp, Units::Metric, None, None)).await;
+        assert_eq!(lookup.unwrap_err().to_string(), "the weather service timed out");
+    }
+
+    #[tokio::test]
+    async fn units_follow_the_saved_location_country() {
+        let us = LONDON_J1.replace("United Kingdom", "United States of America");
+        let fetcher = CannedFetcher::default().respond("Portland", 200, &us).respond("Nowhere", 200, "{}").respond("wttr.in", 200, LONDON_J1);
+        let (bot, log) = bot_with(&["--units", "both"], fetcher);
+        bot.save_location("alice", "London");
+        bot.save_location("bob", "Portland");
+        bot.save_location("carol", "Nowhere");
+        assert_eq!(bot.units_for("alice").await, Units::Metric);
+        assert_eq!(bot.units_for("bob").await, Units::Imperial);
+        assert_eq!(bot.units_for("carol").await, Units::Both);
+        assert_eq!(bot.units_for("dave").await, Units::Both);
+
+        // Every inference, including carol's missing country, is kept with the location.
+        let fetches = log.urls.lock().unwrap().len();
+        assert_eq!(bot.units_for("bob").await, Units::Imperial);
+        assert_eq!(bot.units_for("carol").await, Units::Both);
+        assert_eq!(log.urls.lock().unwrap().len(), fetches);
+    }
+
+    #[test]
+    fn temp_shortcuts_ask_for_the_temp_report() {
+        let bot = bot(&[]);
+        assert_eq!(bot.split_report("!t London"), ("!w London".to_string(), Report::Temp));
+        assert_eq!(bot.split_report("!w London temp"), ("!w London".to_string(), Report::Temp));
+    }
+
+    #[tokio::test]
+    async fn nick_lang_beats_channel_lang_beats_default() {
+        let (bot, log) = bot_with(&["--lang", "fr"], CannedFetcher::london());
+        bot.nick_langs.lock().unwrap().insert("alice".to_string(), "de".to_string());
+        bot.channel_langs.lock().unwrap().insert("#weather".to_string(), "es".to_string());
+        for (nick, channel) in [("alice", "#weather"), ("bob", "#weather"), ("bob", "#other")] {
+            let lang = bot.lang_for(nick, channel);
+            bot.get_weather("London", lang.as_deref()).await.unwrap();
+        }
+        assert_eq!(*log.urls.lock().unwrap(), [
+            "https://wttr.in/London?format=j1&lang=de",
+            "https://wttr.in/London?format=j1&lang=es",
+            "https://wttr.in/London?format=j1&lang=fr",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn failed_sends_do_not_stop_message_handling() {
+        let bot = Arc::new(bot(&[]));
+        let mut client = mock_client().await;
+        // With the outgoing queue gone every send fails, as after the connection drops mid-reply.
+        drop(client.outgoing());
+        let privmsg = |content: &str| format!(":alice!a@example.com PRIVMSG #weather :{}\r\n", content).parse::<Message>().unwrap();
+
+        assert!(bot.handle_message(&client, privmsg("!w source")).await.is_err());
+        bot.process_message(&client, privmsg("!w source")).await;
+        bot.process_message(&client, privmsg("!w Paris")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(bot.nick_locations.lock().unwrap().get("alice").map(|saved| saved.location.as_str()), Some("Paris"));
+        // The report couldn't be sent, so it isn't kept for "!w again".
+        assert!(!bot.last_results.lock().unwrap().contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn whereami_uses_the_host_without_saving() {
+        let geoip_db = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/geoip_city.mmdb");
+        let (bot, log) = bot_with(&["--geoip-db", geoip_db], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let whereami = |host: &str| format!(":alice!a@{} PRIVMSG #weather :!whereami\r\n", host).parse::<Message>().unwrap();
+
+        bot.handle_message(&client, whereami("81.2.69.142")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/51.5142,-0.0931?format=j1"]);
+        assert!(!bot.nick_locations.lock().unwrap().contains_key("alice"));
+
+        assert_eq!(
+            bot.whereami_query("alice", "user/alice"),
+            Err("alice: I couldn't place your host; try !w <city>".to_string())
+        );
+        let (without_geoip, _) = bot_with(&[], CannedFetcher::london());
+        assert_eq!(
+            without_geoip.whereami_query("alice", "81.2.69.142"),
+            Err("alice: I can't look up locations from hosts here; try !w <city>".to_string())
+        );
+    }
+
+    #[test]
+    fn second_check_shows_the_change() {
+        let bot = bot(&[]);
+        let reading = |query: &str, f, c| bot.current_readings.lock().unwrap().insert(query.to_lowercase(), (f, c));
+        reading("London", 68, 20);
+        assert_eq!(bot.reading_change("alice", "London", Units::Imperial), None);
+        reading("London", 72, 22);
+        assert_eq!(
+            bot.reading_change("alice", "london", Units::Imperial).as_deref(),
+            Some("(was 68°F, now 72°F, +4°F since last check)")
+        );
+        // Another location starts over.
+        reading("Paris", 60, 16);
+        assert_eq!(bot.reading_change("alice", "Paris", Units::Imperial), None);
+        assert_eq!(bot.reading_change("alice", "London", Units::Imperial), None);
+    }
+
+    #[test]
+    fn chantypes_decide_what_is_a_channel() {
+        let bot = bot(&[]);
+        assert!(bot.is_channel("&local"));
+        assert!(!bot.is_channel("+modeless"));
+        assert_eq!(bot.parse_relay_command("!wto +modeless London"), None);
+
+        let isupport: Vec<String> = ["rirc", "CHANTYPES=&+", "NICKLEN=30", "are supported by this server"].map(String::from).into();
+        *bot.chantypes.lock().unwrap() = bot.parse_chantypes(&isupport);
+        assert!(bot.is_channel("&local"));
+        assert!(bot.is_channel("+modeless"));
+        assert!(!bot.is_channel("#weather"));
+        assert!(!bot.is_channel("alice"));
+        assert_eq!(bot.parse_relay_command("!wto &local London"), Some(("&local".to_string(), "!w London".to_string())));
+        assert_eq!(bot.parse_relay_command("!wto #weather London"), None);
+    }
+
+    #[tokio::test]
+    async fn addresses_reach_the_geocoder_intact() {
+        let bot = bot(&[]);
+        let location = |query: &str| Some(WeatherQuery::Location { query: query.to_string(), save: true, nick: None });
+        assert_eq!(bot.parse_weather_query("!w 8FVC9G8F+6X"), location("8FVC9G8F+6X"));
+        assert_eq!(bot.parse_weather_query("!w 9G8F+6X Zurich"), location("9G8F+6X+Zurich"));
+        assert_eq!(bot.parse_weather_query("!w filled.count.soap"), location("filled.count.soap"));
+        assert_eq!(bot.parse_weather_query("!w ///filled.count.soap"), location("///filled.count.soap"));
+        // Ordinary places and postcodes still take their usual routes.
+        assert_eq!(
+            bot.parse_weather_query("!w Portland, OR"),
+            Some(WeatherQuery::Location { query: "Portland+OR".to_string(), save: true, nick: None })
+        );
+        assert_eq!(
+            bot.parse_weather_query("!w 90210"),
+            Some(WeatherQuery::Location { query: "90210,+USA".to_string(), save: true, nick: Some("90210".to_string()) })
+        );
+
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        bot.get_weather("8FVC9G8F+6X", None).await.unwrap();
+        bot.get_weather("London", None).await.unwrap();
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/47.365563,8.524938?format=j1", "https://wttr.in/London?format=j1"]);
+    }
+
+    #[tokio::test]
+    async fn only_fresh_channel_lookups_are_counted() {
+        let (bot, _log) = bot_with(&[], CannedFetcher::london());
+        let bot = Arc::new(bot);
+        let client = mock_client().await;
+        let privmsg = |target: &str, content: &str| format!(":alice!a@example.com PRIVMSG {} :{}\r\n", target, content).parse::<Message>().unwrap();
+
+        bot.handle_message(&client, privmsg("rirc", "!w London")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bot.last_results.lock().unwrap().contains_key("alice"));
+        assert!(bot.query_counts.lock().unwrap().is_empty());
+
+        bot.handle_message(&client, privmsg("#weather", "!w London")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        bot.last_results.lock().unwrap().get_mut("alice").unwrap().at -= Duration::from_secs(LAST_RESULT_TTL_SECS);
+        bot.handle_message(&client, privmsg("#weather", "!w again")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let counts = bot.query_counts.lock().unwrap().clone();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts["#weather"]["London"], 1);
+    }
+}
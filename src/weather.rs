@@ -0,0 +1,2360 @@
+//! Fetching from wttr.in and Open-Meteo, parsing the responses and formatting reports.
+
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+use chrono_tz::Tz;
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use futures_util::future::BoxFuture;
+use crate::bot::{Reading, WeatherBot};
+use crate::cli::{ConditionKind, Units};
+
+/// Condition codes for thunderstorms, blizzards, heavy snow and torrential rain.
+const SEVERE_CODES: &[i32] = &[200, 227, 230, 359, 377, 386, 389, 392, 395];
+
+/// Unregisters an in-flight fetch even if the fetching task is cancelled, so waiters see the
+/// channel close instead of hanging. Disarmed once the fetch has removed its own entry, since
+/// by then the key may belong to the next fetch for the same query.
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, broadcast::Sender<Result<Value, String>>>>,
+    key: String,
+    armed: bool,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.in_flight.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+/// The wttr.in circuit breaker. After --breaker-threshold consecutive failures it opens and
+/// fetches fail fast; once --breaker-cooldown passes, one request is let through to test
+/// whether wttr.in is back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// The test request is in flight; everything else still fails fast.
+    HalfOpen,
+}
+
+/// Records a wttr.in fetch with the circuit breaker. A fetch dropped before `succeeded` is set
+/// (say, abandoned by --command-timeout) counts as a failure.
+struct FetchAttempt<'a> {
+    bot: &'a WeatherBot,
+    succeeded: bool,
+}
+
+impl Drop for FetchAttempt<'_> {
+    fn drop(&mut self) {
+        self.bot.record_fetch(self.succeeded);
+    }
+}
+
+/// What the bot needs back from an HTTP GET; the 429 handling lives in `WeatherBot::fetch`.
+#[derive(Clone)]
+pub struct HttpResponse {
+    status: u16,
+    /// Retry-After in seconds, when the server sent one.
+    retry_after: Option<u64>,
+    body: String,
+}
+
+/// The bot's only way onto the network, so it can be swapped for canned responses.
+pub trait Fetcher: Send + Sync {
+    fn get(&self, url: &str) -> BoxFuture<'_, Result<HttpResponse, Box<dyn Error + Send + Sync>>>;
+}
+
+pub struct ReqwestFetcher(pub reqwest::Client);
+
+impl Fetcher for ReqwestFetcher {
+    fn get(&self, url: &str) -> BoxFuture<'_, Result<HttpResponse, Box<dyn Error + Send + Sync>>> {
+        let request = self.0.get(url);
+        Box::pin(async move {
+            let response = request.send().await?;
+            let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok());
+            Ok(HttpResponse {
+                status: response.status().as_u16(),
+                retry_after,
+                body: response.text().await?,
+            })
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Report {
+    /// The standard report; `hour` overrides --forecast-hour for the future days' conditions
+    Full { hour: Option<u32> },
+    /// Temperatures over the next few 3-hour steps, e.g. 72°→75°→78°↑
+    Trend,
+    /// Current conditions plus wind, gusts, pressure and visibility (the !wx command)
+    Detailed,
+    /// wttr.in's own one-liner (format=3), e.g. "London: ⛅️ +15°C"
+    Short,
+    /// Yes/no on rain for the rest of today (the !rain command)
+    Rain,
+    /// Today's heating and cooling degree days against --degree-day-base
+    DegreeDays,
+    /// Expected snowfall and chance of snow for each forecast day (the !snow command)
+    Snow,
+    /// The likeliest kind of precipitation and its chance for each forecast day (the !precip command)
+    Precip,
+    /// Sea temperature and wave height near the location, with --marine
+    Marine,
+    /// A single forecast day picked by offset, 0 being today (e.g. "!w London 2")
+    Day { offset: usize },
+    /// Just the current temperature, feels-like and conditions on one line (the !t command)
+    Temp,
+    /// The forecast days as columns of high, low and conditions (e.g. "!w London table"),
+    /// padded with spaces; only lines up in clients using a monospace font
+    Table,
+    /// Each forecast day's low-high range and nothing else (e.g. "!w London ranges")
+    Ranges,
+    /// The warmest and coolest hourly entries still to come, and when (e.g. "!w London extremes")
+    Extremes,
+    /// What to wear for the current conditions (the !wear command)
+    Wear,
+    /// One CSV row per forecast day for logs and spreadsheets (e.g. "!w London csv"); only
+    /// answered in private messages, or to admins
+    Csv,
+}
+
+/// A temperature in both systems, as wttr.in reports them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Temp {
+    f: i32,
+    c: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Conditions {
+    description: String,
+    code: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct CurrentConditions {
+    temp: Temp,
+    feels_like: Option<Temp>,
+    humidity: Option<i32>,
+    conditions: Conditions,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct HourlyForecast {
+    /// wttr.in's "time": hours * 100, e.g. 1500
+    time: u32,
+    temp: Temp,
+    humidity: Option<i32>,
+    conditions: Conditions,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct DayForecast {
+    date: Option<String>,
+    high: Option<Temp>,
+    low: Option<Temp>,
+    /// The average high for this date, filled in by --climate-normals; wttr.in doesn't send it.
+    normal_high: Option<Temp>,
+    /// Only the entries that parsed; look them up by `time`, not position.
+    hourly: Vec<HourlyForecast>,
+}
+
+/// The parts of a wttr.in j1 response the standard report and JSON output use. Anything
+/// missing or malformed is None (or left out) so the rest can still be shown.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Forecast {
+    location: Option<String>,
+    /// The location's observation time as an hourly "time" value, e.g. 1500
+    local_time: Option<u32>,
+    current: Option<CurrentConditions>,
+    /// Today first; wttr.in sends three days.
+    days: Vec<DayForecast>,
+}
+
+const TREND_STEPS: usize = 5;
+
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+/// ANSI foreground codes for mIRC colors 0-15; the background code is 10 more.
+const ANSI_COLORS: [u8; 16] = [97, 30, 34, 32, 91, 31, 35, 33, 93, 92, 36, 96, 94, 95, 90, 37];
+
+/// Where a reply formatted for IRC is shown instead, e.g. --dry-run's stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorTarget {
+    /// A terminal: mIRC formatting becomes ANSI escape sequences
+    Ansi,
+    /// Anything else: mIRC formatting is dropped
+    Plain,
+}
+
+/// Hourly chanceofrain at which !rain answers yes.
+const RAIN_LIKELY_PERCENT: u32 = 50;
+
+/// Open Location Code digits, in value order.
+const PLUS_CODE_ALPHABET: &str = "23456789CFGHJMPQRVWX";
+
+/// Countries still reporting temperatures in °F, as wttr.in names them in nearest_area.
+const FAHRENHEIT_COUNTRIES: &[&str] = &[
+    "United States of America", "Liberia", "Bahamas", "Belize", "Cayman Islands", "Palau",
+    "Micronesia", "Marshall Islands", "Puerto Rico", "Guam", "US Virgin Islands",
+];
+
+impl WeatherBot {
+    /// Returns an alert when the channel's location turns severe; repeats of the same code are
+    /// suppressed until conditions clear.
+    pub fn check_severe(&self, channel: &str, response: &Value, query: &str) -> Option<String> {
+        let current = &response["current_condition"][0];
+        let code = current["weatherCode"].as_str().unwrap_or("").parse::<i32>().unwrap_or(0);
+        let mut last_alerts = self.last_alerts.lock().unwrap();
+        if !SEVERE_CODES.contains(&code) {
+            last_alerts.remove(channel);
+            return None;
+        }
+        if last_alerts.insert(channel.to_string(), code) == Some(code) {
+            return None;
+        }
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let conditions = current["weatherDesc"][0]["value"].as_str().unwrap_or("Unknown");
+        Some(format!("{} in {}: {} {}", self.format_severe_prefix(), location, self.get_condition_emoji(code), conditions))
+    }
+
+    /// "⚠️ Severe weather" in bold red, so the alert stands out even where the emoji doesn't render.
+    fn format_severe_prefix(&self) -> String {
+        format!("\x02\x0304{} Severe weather\x0F", self.emoji("\u{26A0}\u{FE0F}"))
+    }
+
+    /// `home` is the asker's "!w tz" choice; times are shown there rather than at the location.
+    pub async fn get_report(&self, query: &str, report: Report, units: Units, lang: Option<&str>, home: Option<Tz>) -> Result<String, Box<dyn Error>> {
+        match report {
+            Report::Full { hour } => {
+                self.get_full_report(query, hour.unwrap_or(self.forecast_hour), units, lang).await
+            }
+            Report::Trend => self.get_weather(query, lang).await.map(|data| self.format_trend(&data, query, units)),
+            Report::Detailed => self.get_weather(query, lang).await.map(|data| self.format_detailed(&data, query, units, home)),
+            Report::Short => self.get_short_weather(query, lang).await,
+            Report::Rain => self.get_weather(query, lang).await.map(|data| self.format_rain(&data, query, home)),
+            Report::DegreeDays => self.get_weather(query, lang).await.map(|data| self.format_degree_days(&data, query)),
+            Report::Snow => self.get_weather(query, lang).await.map(|data| self.format_snow(&data, query, units)),
+            Report::Precip => self.get_weather(query, lang).await.map(|data| self.format_precip(&data, query)),
+            Report::Marine => self.get_marine(query, units, lang).await,
+            Report::Day { offset } => {
+                let data = self.get_weather(query, lang).await?;
+                self.format_day_report(&self.parse_forecast(&data)?, query, offset, units)
+            }
+            Report::Temp => {
+                let data = self.get_weather(query, lang).await?;
+                Ok(self.format_temp_line(&self.parse_forecast(&data)?, query, units))
+            }
+            Report::Table => {
+                let data = self.get_weather(query, lang).await?;
+                Ok(self.format_table(&self.parse_forecast(&data)?, query, units))
+            }
+            Report::Ranges => {
+                let data = self.get_weather(query, lang).await?;
+                Ok(self.format_ranges(&self.parse_forecast(&data)?, query, units))
+            }
+            Report::Wear => self.get_weather(query, lang).await.map(|data| self.format_wear(&data, query, units)),
+            Report::Extremes => {
+                let data = self.get_weather(query, lang).await?;
+                Ok(self.format_extremes(&self.parse_forecast(&data)?, query, units))
+            }
+            Report::Csv => {
+                let data = self.get_weather(query, lang).await?;
+                Ok(self.format_csv(&self.parse_forecast(&data)?, query))
+            }
+        }
+    }
+
+    /// Imperial for a location in a °F country, metric anywhere else; None if wttr.in didn't
+    /// name the country.
+    pub fn infer_units(&self, response: &Value) -> Option<Units> {
+        let country = response["nearest_area"][0]["country"][0]["value"].as_str()?;
+        Some(if FAHRENHEIT_COUNTRIES.iter().any(|c| c.eq_ignore_ascii_case(country)) { Units::Imperial } else { Units::Metric })
+    }
+
+    /// Compares the queried city against the caller's saved location, e.g.
+    /// "Paris is 5°F warmer than London (Sunny vs Partly cloudy)".
+    pub fn format_diff(&self, home: &Value, home_query: &str, other: &Value, other_query: &str, units: Units) -> String {
+        let reading = |response: &Value, query: &str| {
+            let current = &response["current_condition"][0];
+            (
+                response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query).to_string(),
+                current["temp_F"].as_str().unwrap_or("N/A").parse::<i32>().unwrap_or(0),
+                current["temp_C"].as_str().unwrap_or("N/A").parse::<i32>().unwrap_or(0),
+                current["weatherDesc"][0]["value"].as_str().unwrap_or("Unknown").to_string(),
+            )
+        };
+        let (home_name, home_f, home_c, home_conditions) = reading(home, home_query);
+        let (other_name, other_f, other_c, other_conditions) = reading(other, other_query);
+
+        let (delta_f, delta_c) = (other_f - home_f, other_c - home_c);
+        let comparison = match delta_f.signum() {
+            1 => format!("{} warmer than", self.format_temp(delta_f, delta_c, units)),
+            -1 => format!("{} colder than", self.format_temp(-delta_f, -delta_c, units)),
+            _ => "the same temperature as".to_string(),
+        };
+        format!("{} is {} {} ({} vs {})", other_name, comparison, home_name, other_conditions, home_conditions)
+    }
+
+    /// Identical concurrent queries (same location and language) share a single fetch: the
+    /// first caller fetches and broadcasts the result to everyone who asked while it was running.
+    pub async fn get_weather(&self, query: &str, lang: Option<&str>) -> Result<Value, Box<dyn Error>> {
+        let key = format!("{}|{}", query.to_lowercase(), lang.unwrap_or_default());
+        let waiter = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(tx) => Some(tx.subscribe()),
+                None => {
+                    in_flight.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+        if let Some(mut rx) = waiter {
+            return Ok(rx.recv().await??);
+        }
+
+        let mut guard = InFlightGuard { in_flight: &self.in_flight, key, armed: true };
+        let result = self.fetch_weather(query, lang).await.map_err(|e| e.to_string());
+        let tx = self.in_flight.lock().unwrap().remove(&guard.key);
+        guard.armed = false;
+        if let Some(tx) = tx {
+            let _ = tx.send(result.clone());
+        }
+        Ok(result?)
+    }
+
+    async fn fetch_weather(&self, query: &str, lang: Option<&str>) -> Result<Value, Box<dyn Error>> {
+        let query = self.resolve_location(query).await;
+        let body = self.fetch(&self.weather_url(&query, "j1", lang)).await?;
+        let mut data = serde_json::from_str(&body)?;
+        if let Some(lang) = lang {
+            self.localize_conditions(&mut data, lang);
+        }
+        Ok(data)
+    }
+
+    /// With lang=, wttr.in sends the translated condition text as "lang_<code>" beside each
+    /// weatherDesc; swapping it in means every report picks it up unchanged.
+    fn localize_conditions(&self, data: &mut Value, lang: &str) {
+        let key = format!("lang_{}", lang);
+        for (field, value) in data.as_object_mut().into_iter().flatten() {
+            let entries: Vec<&mut Value> = match field.as_str() {
+                "current_condition" => value.as_array_mut().into_iter().flatten().collect(),
+                "weather" => value.as_array_mut().into_iter().flatten()
+                    .filter_map(|day| day.get_mut("hourly")?.as_array_mut())
+                    .flatten()
+                    .collect(),
+                _ => continue,
+            };
+            for entry in entries {
+                if let Some(translated) = entry.get(&key).cloned() {
+                    entry["weatherDesc"] = translated;
+                }
+            }
+        }
+    }
+
+    /// Fetches wttr.in's plain-text one-liner, with any ANSI escapes removed.
+    async fn get_short_weather(&self, query: &str, lang: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let query = self.resolve_location(query).await;
+        let body = self.fetch(&self.weather_url(&query, "3", lang)).await?;
+        let re_ansi = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap();
+        Ok(re_ansi.replace_all(body.trim(), "").into_owned())
+    }
+
+    /// With --geocode, swaps a place name for precise coordinates so wttr.in doesn't have to
+    /// guess. Landmarks, airport codes and anything containing digits (postal codes,
+    /// coordinates) pass through, as does any name the geocoder can't resolve.
+    async fn resolve_location(&self, query: &str) -> String {
+        if let Some(coordinates) = self.resolve_address(query).await {
+            return coordinates;
+        }
+        let is_airport = query.len() == 3 && query.chars().all(|c| c.is_ascii_uppercase());
+        let passthrough = query.starts_with('~') || is_airport || query.chars().any(|c| c.is_ascii_digit());
+        if !self.geocode || passthrough {
+            return query.to_string();
+        }
+        if let Some(coordinates) = self.geocode_cache.lock().unwrap().get(query) {
+            return coordinates.clone();
+        }
+        match self.geocode(query).await {
+            Ok(Some(coordinates)) => {
+                self.geocode_cache.lock().unwrap().insert(query.to_string(), coordinates.clone());
+                coordinates
+            }
+            Ok(None) => query.to_string(),
+            Err(e) => {
+                eprintln!("Geocoding {} failed: {}", query, e);
+                query.to_string()
+            }
+        }
+    }
+
+    async fn get_full_report(&self, query: &str, hour: u32, units: Units, lang: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let forecast = self.get_full_forecast(query, lang).await?;
+        Ok(self.format_response(&forecast, query, hour, units))
+    }
+
+    /// The forecast behind the full report, with today's climate normal under --climate-normals.
+    pub async fn get_full_forecast(&self, query: &str, lang: Option<&str>) -> Result<Forecast, Box<dyn Error>> {
+        let data = self.get_weather(query, lang).await?;
+        let mut forecast = self.parse_forecast(&data)?;
+        if self.climate_normals {
+            if let Some(today) = forecast.days.first_mut() {
+                // The note is an extra; if the archive can't be reached the report goes out without it.
+                today.normal_high = match self.get_normal_high(&data, today.date.as_deref()).await {
+                    Ok(normal) => normal,
+                    Err(e) => {
+                        eprintln!("Error fetching climate normals for {}: {}", query, e);
+                        None
+                    }
+                };
+            }
+        }
+        if let Some(current) = &forecast.current {
+            self.current_readings.lock().unwrap().insert(query.to_lowercase(), (current.temp.f, current.temp.c));
+        }
+        Ok(forecast)
+    }
+
+    /// The 1991-2020 average high for `date`'s month and day at the coordinates wttr.in resolved
+    /// the query to, or None without coordinates or a date.
+    async fn get_normal_high(&self, data: &Value, date: Option<&str>) -> Result<Option<Temp>, Box<dyn Error>> {
+        let area = &data["nearest_area"][0];
+        let (Some(latitude), Some(longitude), Some(month_day)) =
+            (area["latitude"].as_str(), area["longitude"].as_str(), date.and_then(|date| date.get(4..)))
+        else {
+            return Ok(None);
+        };
+        let key = format!("{},{} {}", latitude, longitude, month_day);
+        let cached = self.normals_cache.lock().unwrap().get(&key).copied();
+        let normal_c = match cached {
+            Some(normal_c) => normal_c,
+            None => {
+                let url = reqwest::Url::parse_with_params(
+                    "https://archive-api.open-meteo.com/v1/archive",
+                    &[
+                        ("latitude", latitude), ("longitude", longitude),
+                        ("start_date", "1991-01-01"), ("end_date", "2020-12-31"),
+                        ("daily", "temperature_2m_max"),
+                    ],
+                )?;
+                let response = self.fetcher.get(url.as_str()).await.map_err(|e| e as Box<dyn Error>)?;
+                if response.status != 200 {
+                    return Err(format!("climate archive returned HTTP {}", response.status).into());
+                }
+                let Some(normal_c) = self.climate_normal(&serde_json::from_str(&response.body)?, month_day) else {
+                    return Ok(None);
+                };
+                self.normals_cache.lock().unwrap().insert(key, normal_c);
+                normal_c
+            }
+        };
+        Ok(Some(Temp { f: (normal_c * 9.0 / 5.0 + 32.0).round() as i32, c: normal_c.round() as i32 }))
+    }
+
+    /// Averages an archive response's daily highs falling on `month_day` ("-10-15") across years.
+    fn climate_normal(&self, archive: &Value, month_day: &str) -> Option<f64> {
+        let highs: Vec<f64> = archive["daily"]["time"].as_array()?.iter()
+            .zip(archive["daily"]["temperature_2m_max"].as_array()?)
+            .filter(|(day, _)| day.as_str().is_some_and(|day| day.ends_with(month_day)))
+            .filter_map(|(_, high)| high.as_f64())
+            .collect();
+        (!highs.is_empty()).then(|| highs.iter().sum::<f64>() / highs.len() as f64)
+    }
+
+    /// "(+5°F vs normal)" for a high above the date's average.
+    fn format_vs_normal(&self, high: Temp, normal: Temp, units: Units) -> String {
+        let (delta_f, delta_c) = (high.f - normal.f, high.c - normal.c);
+        let sign = if delta_f < 0 { "-" } else { "+" };
+        format!("({}{} vs normal)", sign, self.format_temp(delta_f.abs(), delta_c.abs(), units))
+    }
+
+    /// Looks up marine conditions at the coordinates wttr.in resolved the query to.
+    async fn get_marine(&self, query: &str, units: Units, lang: Option<&str>) -> Result<String, Box<dyn Error>> {
+        if !self.marine {
+            return Err("marine reports aren't enabled on this bot.".into());
+        }
+        let data = self.get_weather(query, lang).await?;
+        let location = data["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let area = &data["nearest_area"][0];
+        let (Some(latitude), Some(longitude)) = (area["latitude"].as_str(), area["longitude"].as_str()) else {
+            return Ok(self.format_marine(location, &Value::Null, units));
+        };
+        let url = reqwest::Url::parse_with_params(
+            "https://marine-api.open-meteo.com/v1/marine",
+            &[("latitude", latitude), ("longitude", longitude), ("current", "sea_surface_temperature,wave_height")],
+        )?;
+        let response = self.fetcher.get(url.as_str()).await.map_err(|e| e as Box<dyn Error>)?;
+        // Inland points come back as a 400 or with null readings; both mean "no marine data".
+        let marine = if response.status == 200 { serde_json::from_str(&response.body)? } else { Value::Null };
+        Ok(self.format_marine(location, &marine, units))
+    }
+
+    fn format_marine(&self, location: &str, marine: &Value, units: Units) -> String {
+        let current = &marine["current"];
+        let mut segments = Vec::new();
+        if let Some(water_c) = current["sea_surface_temperature"].as_f64() {
+            let water_f = water_c * 9.0 / 5.0 + 32.0;
+            segments.push(format!("Water: {}", self.format_temp(water_f.round() as i32, water_c.round() as i32, units)));
+        }
+        if let Some(waves_m) = current["wave_height"].as_f64() {
+            let waves = match units {
+                Units::Imperial => format!("{:.1} ft", waves_m * 3.281),
+                Units::Metric => format!("{:.1} m", waves_m),
+                Units::Both => format!("{:.1} ft/{:.1} m", waves_m * 3.281, waves_m),
+            };
+            segments.push(format!("Waves: {}", waves));
+        }
+        if segments.is_empty() {
+            format!("Marine data isn't available for {}", location)
+        } else {
+            format!("{} marine: {}", location, segments.join(". "))
+        }
+    }
+
+    /// Whether `query` (normalized) is a plus code or what3words address for `resolve_address`.
+    pub fn is_address(&self, query: &str) -> bool {
+        parse_plus_code(query).is_some() || is_what3words(query)
+    }
+
+    /// Plus codes and what3words addresses as coordinates, whether or not --geocode is on, since
+    /// wttr.in understands neither. A full plus code ("9C3XGV4C+XV") is decoded directly; a
+    /// short one needs a locality to be geocoded against ("GV4C+XV London"), and a what3words
+    /// address needs --what3words-key. None for anything else, or if the lookup fails.
+    async fn resolve_address(&self, query: &str) -> Option<String> {
+        let lookup = match parse_plus_code(query) {
+            Some((code, None)) => return decode_plus_code(&code).map(|(lat, lon)| format!("{:.6},{:.6}", lat, lon)),
+            Some((code, Some(locality))) => AddressLookup::ShortPlusCode(code, locality),
+            None if is_what3words(query) => AddressLookup::What3Words(query.trim_start_matches('/').to_lowercase()),
+            None => return None,
+        };
+        if let Some(coordinates) = self.geocode_cache.lock().unwrap().get(query) {
+            return Some(coordinates.clone());
+        }
+        let resolved = match lookup {
+            AddressLookup::ShortPlusCode(code, locality) => self.geocode(&locality).await.map(|reference| {
+                let (lat, lon) = reference?.split_once(',').and_then(|(lat, lon)| Some((lat.parse().ok()?, lon.parse().ok()?)))?;
+                let (lat, lon) = recover_plus_code(&code, lat, lon)?;
+                Some(format!("{:.6},{:.6}", lat, lon))
+            }),
+            AddressLookup::What3Words(words) => self.what3words(&words).await,
+        };
+        match resolved {
+            Ok(Some(coordinates)) => {
+                self.geocode_cache.lock().unwrap().insert(query.to_string(), coordinates.clone());
+                Some(coordinates)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Resolving {} failed: {}", query, e);
+                None
+            }
+        }
+    }
+
+    async fn what3words(&self, words: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(key) = &self.what3words_key else {
+            return Err("what3words addresses need --what3words-key".into());
+        };
+        let url = reqwest::Url::parse_with_params(
+            "https://api.what3words.com/v3/convert-to-coordinates",
+            &[("words", words), ("key", key.as_str())],
+        )?;
+        let response = self.fetcher.get(url.as_str()).await.map_err(|e| e as Box<dyn Error>)?;
+        let body: Value = serde_json::from_str(&response.body)?;
+        if let Some(message) = body["error"]["message"].as_str() {
+            return Err(message.into());
+        }
+        let coordinates = &body["coordinates"];
+        Ok(coordinates["lat"].as_f64().zip(coordinates["lng"].as_f64()).map(|(lat, lon)| format!("{},{}", lat, lon)))
+    }
+
+    async fn geocode(&self, query: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let url = reqwest::Url::parse_with_params(
+            "https://geocoding-api.open-meteo.com/v1/search",
+            &[("name", query.replace('+', " ").as_str()), ("count", "1")],
+        )?;
+        let response = self.fetcher.get(url.as_str()).await.map_err(|e| e as Box<dyn Error>)?;
+        Ok(self.parse_geocode(&serde_json::from_str(&response.body)?))
+    }
+
+    fn parse_geocode(&self, response: &Value) -> Option<String> {
+        let place = &response["results"][0];
+        Some(format!("{},{}", place["latitude"].as_f64()?, place["longitude"].as_f64()?))
+    }
+
+    fn weather_url(&self, query: &str, format: &str, lang: Option<&str>) -> String {
+        match lang {
+            Some(lang) => format!("https://wttr.in/{}?format={}&lang={}", query, format, lang),
+            None => format!("https://wttr.in/{}?format={}", query, format),
+        }
+    }
+
+    /// Shared by every wttr.in request: honours the 429 back-off window and the fetch semaphore.
+    async fn fetch(&self, url: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(remaining) = self.rate_limit_remaining() {
+            return Err(format!("wttr.in is rate limiting us, retry in {}s", remaining.as_secs().max(1)).into());
+        }
+
+        let _permit = self.fetch_semaphore.acquire().await?;
+        if !self.circuit_allows() {
+            return Err("the weather service is currently down".into());
+        }
+        let mut attempt = FetchAttempt { bot: self, succeeded: false };
+        let response = self.fetcher.get(url).await.map_err(|e| e as Box<dyn Error>)?;
+        if response.status >= 500 {
+            return Err(format!("wttr.in returned HTTP {}", response.status).into());
+        }
+        attempt.succeeded = true;
+        if response.status == reqwest::StatusCode::TOO_MANY_REQUESTS.as_u16() {
+            let retry_after = response.retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+            *self.blocked_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(retry_after));
+            return Err(format!("wttr.in is rate limiting us, retry in {}s", retry_after).into());
+        }
+        Ok(response.body)
+    }
+
+    /// Whether a fetch may go ahead; an expired open circuit lets this one through as the test.
+    fn circuit_allows(&self) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        match *circuit {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if Instant::now() >= until => {
+                *circuit = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen => false,
+        }
+    }
+
+    fn record_fetch(&self, succeeded: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+        let next = match (*circuit, succeeded) {
+            (CircuitState::HalfOpen, true) => {
+                eprintln!("wttr.in is responding again; resuming requests");
+                CircuitState::Closed { failures: 0 }
+            }
+            (_, true) => CircuitState::Closed { failures: 0 },
+            (CircuitState::Closed { failures }, false)
+                if self.breaker_threshold == 0 || failures + 1 < self.breaker_threshold =>
+            {
+                CircuitState::Closed { failures: failures + 1 }
+            }
+            (CircuitState::Open { until }, false) => CircuitState::Open { until },
+            (_, false) => {
+                eprintln!("wttr.in is failing; pausing requests for {}s", self.breaker_cooldown.as_secs());
+                CircuitState::Open { until: Instant::now() + self.breaker_cooldown }
+            }
+        };
+        *circuit = next;
+    }
+
+    fn rate_limit_remaining(&self) -> Option<Duration> {
+        let blocked_until = (*self.blocked_until.lock().unwrap())?;
+        blocked_until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())
+    }
+
+    /// Pulls the standard report's fields out of a j1 response. Fails only if the response has
+    /// neither current conditions nor forecast days, i.e. isn't a j1 response at all.
+    pub fn parse_forecast(&self, response: &Value) -> Result<Forecast, Box<dyn Error>> {
+        if response["current_condition"].is_null() && response["weather"].is_null() {
+            return Err("unexpected response from wttr.in".into());
+        }
+        let number = |v: &Value| v.as_str().and_then(|s| s.parse::<i32>().ok());
+        let temp = |f: &Value, c: &Value| Some(Temp { f: number(f)?, c: number(c)? });
+        let conditions = |entry: &Value| Conditions {
+            description: entry["weatherDesc"][0]["value"].as_str().unwrap_or("Unknown").to_string(),
+            code: number(&entry["weatherCode"]).unwrap_or(0),
+        };
+
+        let current = &response["current_condition"][0];
+        // Capped here so every report built from the Forecast stays within --max-forecast-days.
+        let days = response["weather"].as_array().into_iter().flatten()
+            .take(self.max_forecast_days)
+            .map(|day| DayForecast {
+                date: day["date"].as_str().map(str::to_string),
+                high: temp(&day["maxtempF"], &day["maxtempC"]),
+                low: temp(&day["mintempF"], &day["mintempC"]),
+                normal_high: None,
+                hourly: day["hourly"].as_array().into_iter().flatten()
+                    .filter_map(|hour| Some(HourlyForecast {
+                        time: hour["time"].as_str()?.parse::<u32>().ok()?,
+                        temp: temp(&hour["tempF"], &hour["tempC"])?,
+                        humidity: number(&hour["humidity"]),
+                        conditions: conditions(hour),
+                    }))
+                    .collect(),
+            })
+            .collect();
+
+        let feels_like = temp(&current["FeelsLikeF"], &current["FeelsLikeC"]);
+        Ok(Forecast {
+            location: response["nearest_area"][0]["areaName"][0]["value"].as_str().map(str::to_string),
+            local_time: current["localObsDateTime"].as_str()
+                .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%d %I:%M %p").ok())
+                .map(|t| t.hour() * 100),
+            current: temp(&current["temp_F"], &current["temp_C"]).map(|temp| CurrentConditions {
+                temp,
+                feels_like,
+                humidity: number(&current["humidity"]),
+                conditions: conditions(current),
+            }),
+            days,
+        })
+    }
+
+    /// Reduces a forecast to the fields the bot reports, for scripting and integrations.
+    pub fn normalize_weather(&self, forecast: &Forecast, query: &str) -> Value {
+        let days: Vec<Value> = forecast.days.iter()
+            .enumerate()
+            .map(|(i, day)| {
+                // Today's noon may already be past, so today uses the next entry still to come.
+                let hourly = if i == 0 { self.upcoming_hourly(forecast) } else { day.hourly.iter().find(|h| h.time == 1200) };
+                json!({
+                    "date": day.date,
+                    "high_f": day.high.map(|t| t.f),
+                    "high_c": day.high.map(|t| t.c),
+                    "low_f": day.low.map(|t| t.f),
+                    "low_c": day.low.map(|t| t.c),
+                    "conditions": hourly.map(|h| &h.conditions.description),
+                    "weather_code": hourly.map(|h| h.conditions.code),
+                })
+            })
+            .collect();
+
+        let current = forecast.current.as_ref();
+        json!({
+            "location": forecast.location.as_deref().unwrap_or(query),
+            "current": {
+                "temp_f": current.map(|c| c.temp.f),
+                "temp_c": current.map(|c| c.temp.c),
+                "humidity": current.and_then(|c| c.humidity),
+                "conditions": current.map(|c| &c.conditions.description),
+                "weather_code": current.map(|c| c.conditions.code),
+            },
+            "forecast": days,
+        })
+    }
+
+    /// "location,date,high_f,high_c,low_f,low_c,conditions" for each forecast day, one per
+    /// line: the fields of the JSON output, so the columns don't depend on the asker's units.
+    /// Unknown values are left empty.
+    pub fn format_csv(&self, forecast: &Forecast, query: &str) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        if forecast.days.is_empty() {
+            return format!("{}: no forecast days are available", location);
+        }
+        let number = |n: Option<i32>| n.map_or(String::new(), |n| n.to_string());
+        forecast.days.iter()
+            .enumerate()
+            .map(|(i, day)| {
+                let hourly = if i == 0 { self.upcoming_hourly(forecast) } else { day.hourly.iter().find(|h| h.time == 1200) };
+                [
+                    csv_field(location),
+                    csv_field(day.date.as_deref().unwrap_or_default()),
+                    number(day.high.map(|t| t.f)),
+                    number(day.high.map(|t| t.c)),
+                    number(day.low.map(|t| t.f)),
+                    number(day.low.map(|t| t.c)),
+                    csv_field(hourly.map_or("", |h| h.conditions.description.as_str())),
+                ].join(",")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Each section renders on its own, so a missing or malformed part of the response (say,
+    /// tomorrow's forecast) is left out rather than turning the whole reply into zeros.
+    pub fn format_response(&self, forecast: &Forecast, query: &str, hour: u32, units: Units) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        let today: Vec<String> = [
+            forecast.current.as_ref().map(|current| self.format_current(current, units)),
+            forecast.days.first().and_then(|day| self.format_high_low(day, units)),
+        ].into_iter().flatten().collect();
+
+        let mut sections = vec![if today.is_empty() { "Current conditions unavailable".to_string() } else { today.join(". ") }];
+        for (offset, day) in forecast.days.iter().enumerate().skip(1) {
+            let label = match offset {
+                1 => "Tomorrow".to_string(),
+                2 => "Day After".to_string(),
+                _ => self.weekday(day, offset),
+            };
+            if let Some(day) = self.format_day(day, hour, units) {
+                sections.push(format!("{}: {}", label, day));
+            }
+        }
+        // wttr.in occasionally resolves a location but sends no (or fewer) forecast days.
+        if sections.len() == 1 && self.max_forecast_days > 1 {
+            sections[0].push_str(" (forecast unavailable)");
+        }
+        format!("{}: {}", location, sections.join(" | "))
+    }
+
+    /// The forecast day at `offset`, with its conditions at --forecast-hour. wttr.in only
+    /// forecasts three days, so anything past the last one is refused.
+    fn format_day_report(&self, forecast: &Forecast, query: &str, offset: usize, units: Units) -> Result<String, Box<dyn Error>> {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        let Some(day) = forecast.days.get(offset) else {
+            return Err(match forecast.days.len() {
+                0 => "no forecast days are available".into(),
+                n => format!("only days 0-{} are available", n - 1).into(),
+            });
+        };
+        let label = ["Today", "Tomorrow", "Day After"].get(offset).copied().unwrap_or("Day");
+        let date = day.date.as_deref().map(|date| format!(" ({})", date)).unwrap_or_default();
+        let summary = self.format_day(day, self.forecast_hour, units).unwrap_or_else(|| "Forecast unavailable".to_string());
+        Ok(format!("{}: {}{}: {}", location, label, date, summary))
+    }
+
+    /// "London: 15°C (feels 13°C), partly cloudy", for a glance without the forecast.
+    pub fn format_temp_line(&self, forecast: &Forecast, query: &str, units: Units) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        let Some(current) = &forecast.current else {
+            return format!("{}: current conditions unavailable", location);
+        };
+        let feels_like = current.feels_like
+            .map(|feels| format!(" (feels {})", self.format_temp(feels.f, feels.c, units)))
+            .unwrap_or_default();
+        format!(
+            "{}: {}{}, {}",
+            location, self.format_temp(current.temp.f, current.temp.c, units), feels_like, current.conditions.description.to_lowercase()
+        )
+    }
+
+    /// "London: Today 58–75°F, Tomorrow 55–72°F, Sat 50–68°F"; days missing a high or low are
+    /// left out.
+    fn format_ranges(&self, forecast: &Forecast, query: &str, units: Units) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        let range = |low: Temp, high: Temp| match units {
+            Units::Imperial => format!("{}\u{2013}{}\u{00B0}F", low.f, high.f),
+            Units::Metric => format!("{}\u{2013}{}\u{00B0}C", low.c, high.c),
+            Units::Both => format!("{}\u{2013}{}\u{00B0}F/{}\u{2013}{}\u{00B0}C", low.f, high.f, low.c, high.c),
+        };
+        let ranges: Vec<String> = forecast.days.iter().enumerate()
+            .filter_map(|(offset, day)| {
+                let name = match offset {
+                    0 => "Today".to_string(),
+                    1 => "Tomorrow".to_string(),
+                    _ => self.weekday(day, offset),
+                };
+                Some(format!("{} {}", name, range(day.low?, day.high?)))
+            })
+            .collect();
+        if ranges.is_empty() {
+            return format!("{}: no forecast ranges available", location);
+        }
+        format!("{}: {}", location, ranges.join(", "))
+    }
+
+    /// "London: Warmest 75°F tomorrow 15:00, coolest 50°F Sat 06:00", across every hourly entry from
+    /// the observation time on; the earliest wins a tie.
+    fn format_extremes(&self, forecast: &Forecast, query: &str, units: Units) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        let now = forecast.local_time.unwrap_or(0);
+        let hours: Vec<(usize, &DayForecast, &HourlyForecast)> = forecast.days.iter().enumerate()
+            .flat_map(|(offset, day)| day.hourly.iter().map(move |hour| (offset, day, hour)))
+            // Keep the entry covering the observation time; hourly steps are 300 apart.
+            .filter(|(offset, _, hour)| *offset > 0 || hour.time + 300 > now)
+            .collect();
+        let warmest = hours.iter().rev().max_by_key(|(_, _, hour)| hour.temp.f);
+        let coolest = hours.iter().min_by_key(|(_, _, hour)| hour.temp.f);
+        let (Some(warmest), Some(coolest)) = (warmest, coolest) else {
+            return format!("{}: no hourly forecast available", location);
+        };
+        let describe = |&(offset, day, hour): &(usize, &DayForecast, &HourlyForecast)| {
+            let name = match offset {
+                0 => "today".to_string(),
+                1 => "tomorrow".to_string(),
+                _ => self.weekday(day, offset),
+            };
+            format!("{} {} {:02}:00", self.format_temp(hour.temp.f, hour.temp.c, units), name, hour.time / 100)
+        };
+        format!("{}: Warmest {}, coolest {}", location, describe(warmest), describe(coolest))
+    }
+
+    /// The day's short weekday name, e.g. "Sat", or "Day 2" without a usable date.
+    fn weekday(&self, day: &DayForecast, offset: usize) -> String {
+        day.date.as_deref()
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+            .map_or(format!("Day {}", offset), |date| date.format("%a").to_string())
+    }
+
+    /// The location, then one line per row of a table with a column per forecast day:
+    ///
+    /// ```text
+    /// London
+    ///       Today          Sat    Sun
+    /// High  18°C           20°C   17°C
+    /// Low   11°C           12°C   10°C
+    /// Cond  Partly cloudy  Sunny  Light rain
+    /// ```
+    ///
+    /// Cells are padded by character count and left uncolored so the columns line up.
+    fn format_table(&self, forecast: &Forecast, query: &str, units: Units) -> String {
+        let location = forecast.location.as_deref().unwrap_or(query);
+        if forecast.days.is_empty() {
+            return format!("{}: no forecast days are available", location);
+        }
+        let index = self.hourly_index(self.forecast_hour);
+        let temp = |t: Option<Temp>| t.map_or("N/A".to_string(), |t| self.format_temp(t.f, t.c, units));
+        let columns: Vec<[String; 4]> = forecast.days.iter().enumerate()
+            .map(|(offset, day)| {
+                let name = if offset == 0 { "Today".to_string() } else { self.weekday(day, offset) };
+                let conditions = day.hourly.iter()
+                    .find(|h| h.time == index as u32 * 300)
+                    .map_or("N/A".to_string(), |h| h.conditions.description.clone());
+                [name, temp(day.high), temp(day.low), conditions]
+            })
+            .collect();
+
+        let labels = ["", "High", "Low", "Cond"];
+        let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = columns.iter()
+            .map(|column| column.iter().map(|cell| cell.chars().count()).max().unwrap_or(0))
+            .collect();
+        let rows = labels.iter().enumerate().map(|(row, label)| {
+            let cells: Vec<String> = columns.iter().zip(&widths)
+                .map(|(column, width)| format!("{:<width$}", column[row], width = width))
+                .collect();
+            format!("{:<width$}  {}", label, cells.join("  "), width = label_width).trim_end().to_string()
+        });
+        std::iter::once(location.to_string()).chain(rows).collect::<Vec<_>>().join("\n")
+    }
+
+    fn format_current(&self, current: &CurrentConditions, units: Units) -> String {
+        let Temp { f: current_temp, c: current_temp_c } = current.temp;
+        let current_humidity = current.humidity.map_or("N/A".to_string(), |h| h.to_string());
+        let comfort = current.humidity
+            .and_then(|humidity| self.comfort(current_temp_c, humidity))
+            .map(|comfort| format!(" ({})", comfort))
+            .unwrap_or_default();
+        let current_temp_emoji = self.get_emoji(current_temp);
+        let current_code = current.conditions.code;
+        let current_emoji = self.get_condition_emoji(current_code);
+        let current_color = self.get_temp_color(current_temp);
+
+        format!(
+            "Conditions: {} \x03{}{}. Humidity: {}%{}. \
+         Temp: {}\x03{}{}\x0F",
+            current_emoji, self.get_condition_color(current_code).as_deref().unwrap_or(current_color), current.conditions.description, current_humidity, comfort,
+            current_temp_emoji, current_color, self.format_spot_temp(current_temp, current_temp_c, units)
+        )
+    }
+
+    /// A forecast day's "High: ... Low: ...", or None without both temperatures.
+    fn format_high_low(&self, day: &DayForecast, units: Units) -> Option<String> {
+        let (high, low) = (day.high?, day.low?);
+        let vs_normal = day.normal_high.map(|normal| format!(" {}", self.format_vs_normal(high, normal, units))).unwrap_or_default();
+        Some(format!(
+            "High: {}\x03{}{}\x0F{}. Low: {}\x03{}{}\x0F",
+            self.get_emoji(high.f), self.get_temp_color(high.f), self.format_temp(high.f, high.c, units), vs_normal,
+            self.get_emoji(low.f), self.get_temp_color(low.f), self.format_temp(low.f, low.c, units)
+        ))
+    }
+
+    /// Summarizes a forecast day, using the hourly entry nearest `hour` for its conditions.
+    /// Without that entry only the high and low are shown; without those, nothing is.
+    fn format_day(&self, day: &DayForecast, hour: u32, units: Units) -> Option<String> {
+        let high_low = self.format_high_low(day, units)?;
+        let index = self.hourly_index(hour);
+        let Some(hourly) = day.hourly.iter().find(|h| h.time == index as u32 * 300) else {
+            return Some(high_low);
+        };
+        let Temp { f: temp, c: temp_c } = hourly.temp;
+        let humidity = hourly.humidity.map_or("N/A".to_string(), |h| h.to_string());
+        let temp_emoji = self.get_emoji(temp);
+        let color = self.get_temp_color(temp);
+        let code = hourly.conditions.code;
+        let emoji = self.get_condition_emoji(code);
+        let label = if index == 4 { "Noon".to_string() } else { format!("{:02}:00", index * 3) };
+
+        Some(format!(
+            "Conditions: {}{}. Humidity: {}%. \
+         {}: {}\x03{}{}\x0F. {}",
+            emoji, self.color_condition(code, &hourly.conditions.description), humidity,
+            label, temp_emoji, color, self.format_spot_temp(temp, temp_c, units),
+            high_low
+        ))
+    }
+
+    /// wttr.in's hourly entries are 3-hourly (00:00, 03:00, ... 21:00); picks the nearest one.
+    fn hourly_index(&self, hour: u32) -> usize {
+        ((hour as usize + 1) / 3).min(7)
+    }
+
+    /// Today's first hourly entry after the location's observation time (the last entry late
+    /// in the evening); noon if the observation time is missing.
+    fn upcoming_hourly<'a>(&self, forecast: &'a Forecast) -> Option<&'a HourlyForecast> {
+        let today = &forecast.days.first()?.hourly;
+        match forecast.local_time {
+            Some(now) => today.iter().find(|h| h.time > now).or(today.last()),
+            None => today.iter().find(|h| h.time == 1200),
+        }
+    }
+
+    /// Describes how the air feels from its dew point (Magnus approximation), using the
+    /// --dry-dewpoint and --muggy-dewpoint thresholds.
+    fn comfort(&self, temp_c: i32, humidity: i32) -> Option<&'static str> {
+        if humidity <= 0 || humidity > 100 {
+            return None;
+        }
+        let (b, c) = (17.62, 243.12);
+        let gamma = (humidity as f64 / 100.0).ln() + b * temp_c as f64 / (c + temp_c as f64);
+        let dewpoint_f = (c * gamma / (b - gamma)) * 9.0 / 5.0 + 32.0;
+        Some(if dewpoint_f < self.dry_dewpoint as f64 {
+            "dry"
+        } else if dewpoint_f >= self.muggy_dewpoint as f64 {
+            "muggy"
+        } else {
+            "comfortable"
+        })
+    }
+
+    /// Walks the hourly entries forward from the location's observation time, rolling into
+    /// tomorrow when today runs out.
+    fn format_trend(&self, response: &Value, query: &str, units: Units) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let now = self.local_time(response);
+        let number = |value: &Value| value.as_str().and_then(|v| v.parse::<i32>().ok()).unwrap_or(0);
+
+        let temps: Vec<(i32, i32)> = response["weather"].as_array().into_iter().flatten()
+            .enumerate()
+            .flat_map(|(day, weather)| weather["hourly"].as_array().into_iter().flatten().map(move |hour| (day, hour)))
+            .filter(|(day, hour)| *day > 0 || hour["time"].as_str().and_then(|t| t.parse::<u32>().ok()).unwrap_or(0) >= now)
+            .take(TREND_STEPS)
+            .map(|(_, hour)| (number(&hour["tempF"]), number(&hour["tempC"])))
+            .collect();
+
+        let (Some(first), Some(last)) = (temps.first(), temps.last()) else {
+            return format!("{}: no hourly forecast available", location);
+        };
+        // The arrow follows the unit shown first.
+        let (first, last) = if units == Units::Metric { (first.1, last.1) } else { (first.0, last.0) };
+        let arrow = if last > first { "\u{2191}" } else if last < first { "\u{2193}" } else { "\u{2192}" };
+        let steps: Vec<String> = temps.iter().map(|&(temp_f, temp_c)| self.format_temp(temp_f, temp_c, units)).collect();
+        format!("{}: {}{}", location, steps.join("\u{2192}"), arrow)
+    }
+
+    /// "London: 12°C, light rain — bring a jacket; umbrella recommended", from the feels-like
+    /// temperature, the wind and today's remaining chance of rain against the --jacket-below,
+    /// --shorts-above, --windy-mph and --umbrella-chance thresholds.
+    fn format_wear(&self, response: &Value, query: &str, units: Units) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let current = &response["current_condition"][0];
+        let number = |value: &Value| value.as_str().and_then(|v| v.parse::<i32>().ok());
+        let (Some(temp_f), Some(temp_c)) = (number(&current["temp_F"]), number(&current["temp_C"])) else {
+            return format!("{}: current conditions unavailable", location);
+        };
+        let feels_f = number(&current["FeelsLikeF"]).unwrap_or(temp_f);
+        let wind_mph = number(&current["windspeedMiles"]).unwrap_or(0);
+        let now = self.local_time(response);
+        let rain_chance = response["weather"][0]["hourly"].as_array().into_iter().flatten()
+            .filter(|hour| number(&hour["time"]).is_some_and(|time| time as u32 + 300 > now))
+            .filter_map(|hour| number(&hour["chanceofrain"]))
+            .max()
+            .unwrap_or(0);
+        let description = current["weatherDesc"][0]["value"].as_str().unwrap_or("Unknown").to_lowercase();
+        let advice = self.wear_advice(feels_f, wind_mph, rain_chance as u32).join("; ");
+        format!("{}: {}, {} \u{2014} {}", location, self.format_temp(temp_f, temp_c, units), description, advice)
+    }
+
+    /// The clothing suggestions for a feels-like temperature (°F), wind speed (mph) and chance
+    /// of rain (%); always at least one.
+    fn wear_advice(&self, feels_f: i32, wind_mph: i32, rain_chance: u32) -> Vec<&'static str> {
+        let mut advice = vec![if feels_f < self.jacket_below {
+            "bring a jacket"
+        } else if feels_f >= self.shorts_above {
+            "shorts weather"
+        } else {
+            "light layers"
+        }];
+        if wind_mph >= self.windy_mph {
+            advice.push("something windproof");
+        }
+        if rain_chance >= self.umbrella_chance {
+            advice.push("umbrella recommended");
+        }
+        advice
+    }
+
+    /// Answers "will it rain today?" from the remaining hourly entries, naming the first one
+    /// likely to see rain.
+    fn format_rain(&self, response: &Value, query: &str, home: Option<Tz>) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let now = self.local_time(response);
+        let time = |hour: &Value| hour["time"].as_str().and_then(|t| t.parse::<u32>().ok()).unwrap_or(0);
+        let chance = |hour: &Value| hour["chanceofrain"].as_str().and_then(|c| c.parse::<u32>().ok()).unwrap_or(0);
+
+        let rainy = response["weather"][0]["hourly"].as_array().into_iter().flatten()
+            // Keep the entry covering the observation time; hourly steps are 300 apart.
+            .filter(|hour| time(hour) + 300 > now)
+            .find(|hour| chance(hour) >= RAIN_LIKELY_PERCENT);
+        match rainy {
+            Some(hour) => {
+                let at = NaiveTime::from_hms_opt(time(hour) / 100, 0, 0).unwrap_or_default();
+                format!("{}: Yes \u{2014} {}% around {}", location, chance(hour), self.format_clock(response, at, home))
+            }
+            None => format!("{}: No rain expected today", location),
+        }
+    }
+
+    /// Each forecast day's total snowfall and its highest hourly chanceofsnow; days without
+    /// totalSnow_cm are left out.
+    fn format_snow(&self, response: &Value, query: &str, units: Units) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let days: Vec<String> = response["weather"].as_array().into_iter().flatten()
+            .take(self.max_forecast_days)
+            .zip(["Today", "Tomorrow", "Day After"])
+            .filter_map(|(day, label)| {
+                let snow_cm = day["totalSnow_cm"].as_str()?.parse::<f64>().ok()?;
+                let chance = day["hourly"].as_array().into_iter().flatten()
+                    .filter_map(|hour| hour["chanceofsnow"].as_str()?.parse::<u32>().ok())
+                    .max()
+                    .map(|chance| format!(" ({}% chance)", chance))
+                    .unwrap_or_default();
+                Some(format!("{}: {}{}", label, self.format_snow_depth(snow_cm, units), chance))
+            })
+            .collect();
+        if days.is_empty() {
+            format!("{}: no snowfall forecast available", location)
+        } else {
+            format!("{}: {}", location, days.join(" | "))
+        }
+    }
+
+    /// Each forecast day's likeliest precipitation, e.g. "Today: 🌨️ 70% snow". A day's chance of
+    /// each kind is its highest hourly chanceof* value; ties go to snow, then sleet.
+    fn format_precip(&self, response: &Value, query: &str) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let days: Vec<String> = response["weather"].as_array().into_iter().flatten()
+            .take(self.max_forecast_days)
+            .zip(["Today", "Tomorrow", "Day After"])
+            .map(|(day, label)| {
+                let chance = |key: &str| day["hourly"].as_array().into_iter().flatten()
+                    .filter_map(|hour| hour[key].as_str()?.parse::<u32>().ok())
+                    .max()
+                    .unwrap_or(0);
+                // wttr.in doesn't always send chanceofsleet; missing counts as 0.
+                let likeliest = [("rain", chance("chanceofrain")), ("sleet", chance("chanceofsleet")), ("snow", chance("chanceofsnow"))]
+                    .into_iter()
+                    .max_by_key(|(_, chance)| *chance)
+                    .filter(|(_, chance)| *chance > 0);
+                match likeliest {
+                    Some((kind, chance)) => format!("{}: {} {}% {}", label, self.precip_emoji(kind), chance, kind),
+                    None => format!("{}: none expected", label),
+                }
+            })
+            .collect();
+        if days.is_empty() {
+            format!("{}: no precipitation forecast available", location)
+        } else {
+            format!("{}: {}", location, days.join(" | "))
+        }
+    }
+
+    /// Rain and snow use their condition emoji; sleet, which shares rain's condition group,
+    /// shows both.
+    fn precip_emoji(&self, kind: &str) -> String {
+        match kind {
+            "snow" => self.kind_emoji(ConditionKind::Snow),
+            "sleet" => self.emoji("🌨️🌧️"),
+            _ => self.kind_emoji(ConditionKind::Rain),
+        }
+    }
+
+    fn format_snow_depth(&self, cm: f64, units: Units) -> String {
+        let inches = cm / 2.54;
+        match units {
+            Units::Imperial => format!("{:.1} in", inches),
+            Units::Metric => format!("{:.1} cm", cm),
+            Units::Both => format!("{:.1} in/{:.1} cm", inches, cm),
+        }
+    }
+
+    fn format_degree_days(&self, response: &Value, query: &str) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let today = &response["weather"][0];
+        let number = |value: &Value| value.as_str().and_then(|v| v.parse::<i32>().ok());
+        let (Some(high), Some(low)) = (number(&today["maxtempF"]), number(&today["mintempF"])) else {
+            return format!("{}: degree days unavailable", location);
+        };
+        let (heating, cooling) = self.degree_days(high, low);
+        format!("{}: {} HDD, {} CDD today (base {}\u{00B0}F)", location, heating, cooling, self.degree_day_base)
+    }
+
+    /// Heating and cooling degree days from the day's high and low (°F), using their mean.
+    fn degree_days(&self, high: i32, low: i32) -> (f64, f64) {
+        let mean = (high + low) as f64 / 2.0;
+        let base = self.degree_day_base as f64;
+        ((base - mean).max(0.0), (mean - base).max(0.0))
+    }
+
+    /// The location's observation time as an hourly "time" value (e.g. 1500), or 0 if unknown.
+    fn local_time(&self, response: &Value) -> u32 {
+        response["current_condition"][0]["localObsDateTime"].as_str()
+            .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%d %I:%M %p").ok())
+            .map_or(0, |t| t.hour() * 100)
+    }
+
+    /// The location's UTC offset: its local observation time less the UTC observation_time
+    /// wttr.in sends alongside, to the nearest quarter hour.
+    fn utc_offset(&self, response: &Value) -> Option<FixedOffset> {
+        let current = &response["current_condition"][0];
+        let local = NaiveDateTime::parse_from_str(current["localObsDateTime"].as_str()?, "%Y-%m-%d %I:%M %p").ok()?;
+        let utc = NaiveTime::parse_from_str(current["observation_time"].as_str()?, "%I:%M %p").ok()?;
+        let minutes = (local.time() - utc).num_minutes();
+        // Only the time of day is known in UTC; a difference outside -12h..+14h crossed midnight.
+        let minutes = if minutes > 14 * 60 { minutes - 24 * 60 } else if minutes < -12 * 60 { minutes + 24 * 60 } else { minutes };
+        FixedOffset::east_opt(((minutes as f64 / 15.0).round() * 15.0 * 60.0) as i32)
+    }
+
+    /// A time of day at the location as "18:05", or with `home` as that moment in the home
+    /// timezone, e.g. "13:05 EDT". Falls back to local time if the location's offset is unknown.
+    fn format_clock(&self, response: &Value, time: NaiveTime, home: Option<Tz>) -> String {
+        let local = || time.format("%H:%M").to_string();
+        let (Some(home), Some(offset)) = (home, self.utc_offset(response)) else {
+            return local();
+        };
+        let date = response["weather"][0]["date"].as_str()
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+            .unwrap_or_default();
+        match offset.from_local_datetime(&date.and_time(time)).single() {
+            Some(moment) => moment.with_timezone(&home).format("%H:%M %Z").to_string(),
+            None => local(),
+        }
+    }
+
+    /// Today's hourly entry covering the observation time.
+    fn current_hourly<'a>(&self, response: &'a Value) -> &'a Value {
+        let now = self.local_time(response);
+        response["weather"][0]["hourly"].as_array().into_iter().flatten()
+            .take_while(|hour| hour["time"].as_str().and_then(|t| t.parse::<u32>().ok()).unwrap_or(0) <= now)
+            .last()
+            .unwrap_or(&response["weather"][0]["hourly"][0])
+    }
+
+    /// Renders a value reported in both systems in the requested units; None if wttr.in
+    /// didn't include it.
+    fn format_measure(&self, imperial: &Value, imperial_unit: &str, metric: &Value, metric_unit: &str, units: Units) -> Option<String> {
+        let (imperial, metric) = (imperial.as_str()?, metric.as_str()?);
+        Some(match units {
+            Units::Imperial => format!("{} {}", imperial, imperial_unit),
+            Units::Metric => format!("{} {}", metric, metric_unit),
+            Units::Both => format!("{} {}/{} {}", imperial, imperial_unit, metric, metric_unit),
+        })
+    }
+
+    /// The compass arrow pointing the way winddirDegree names (0 = N = \u{2191}), to the
+    /// nearest of 8 points.
+    fn wind_arrow(&self, degrees: i32) -> &'static str {
+        const ARROWS: [&str; 8] = ["\u{2191}", "\u{2197}", "\u{2192}", "\u{2198}", "\u{2193}", "\u{2199}", "\u{2190}", "\u{2196}"];
+        ARROWS[((degrees.rem_euclid(360) * 2 + 45) / 90 % 8) as usize]
+    }
+
+    fn format_detailed(&self, response: &Value, query: &str, units: Units, home: Option<Tz>) -> String {
+        let location = response["nearest_area"][0]["areaName"][0]["value"].as_str().unwrap_or(query);
+        let current = &response["current_condition"][0];
+        let hourly = self.current_hourly(response);
+        let temp = current["temp_F"].as_str().unwrap_or("N/A").parse::<i32>().unwrap_or(0);
+        let temp_c = current["temp_C"].as_str().unwrap_or("N/A").parse::<i32>().unwrap_or(0);
+        let code = current["weatherCode"].as_str().unwrap_or("").parse::<i32>().unwrap_or(0);
+
+        let mut segments = vec![
+            format!("{} {}", self.get_condition_emoji(code), self.color_condition(code, current["weatherDesc"][0]["value"].as_str().unwrap_or("Unknown"))),
+            format!("Temp: \x03{}{}\x0F", self.get_temp_color(temp), self.format_spot_temp(temp, temp_c, units)),
+        ];
+        if let Some(range) = self.format_feels_like_range(&response["weather"][0], units) {
+            segments.push(range);
+        }
+        if let Some(humidity) = current["humidity"].as_str() {
+            segments.push(format!("Humidity: {}%", humidity));
+        }
+        if let Some(wind) = self.format_measure(&current["windspeedMiles"], "mph", &current["windspeedKmph"], "km/h", units) {
+            let degrees = current["winddirDegree"].as_str().and_then(|d| d.parse::<i32>().ok());
+            let direction = match degrees {
+                Some(degrees) if self.wind_arrows => Some(self.wind_arrow(degrees)),
+                _ => current["winddir16Point"].as_str(),
+            };
+            let direction = direction.map(|d| format!(" {}", d)).unwrap_or_default();
+            segments.push(format!("Wind: {}{}", wind, direction));
+        }
+        if let Some(gusts) = self.format_measure(&hourly["WindGustMiles"], "mph", &hourly["WindGustKmph"], "km/h", units) {
+            segments.push(format!("Gusts: {}", gusts));
+        }
+        if let Some(pressure) = self.format_measure(&current["pressureInches"], "inHg", &current["pressure"], "hPa", units) {
+            segments.push(format!("Pressure: {}", pressure));
+        }
+        if let Some(visibility) = self.format_measure(&current["visibilityMiles"], "mi", &current["visibility"], "km", units) {
+            segments.push(format!("Visibility: {}", visibility));
+        }
+        for (label, key) in [("Sunrise", "sunrise"), ("Sunset", "sunset")] {
+            // Polar days and nights come through as "No sunrise" and the like, and are skipped.
+            let time = response["weather"][0]["astronomy"][0][key].as_str()
+                .and_then(|time| NaiveTime::parse_from_str(time, "%I:%M %p").ok());
+            if let Some(time) = time {
+                segments.push(format!("{}: {}", label, self.format_clock(response, time, home)));
+            }
+        }
+        format!("{}: {}", location, segments.join(". "))
+    }
+
+    /// "High 88°F (feels 95°F) / Low 70°F (feels 68°F)" from the day's high and low and the
+    /// extremes of its hourly FeelsLike values.
+    fn format_feels_like_range(&self, day: &Value, units: Units) -> Option<String> {
+        let number = |v: &Value| v.as_str().and_then(|s| s.parse::<i32>().ok());
+        let (high, high_c) = (number(&day["maxtempF"])?, number(&day["maxtempC"])?);
+        let (low, low_c) = (number(&day["mintempF"])?, number(&day["mintempC"])?);
+        let (feels_min, feels_max) = self.feels_like_extremes(day)?;
+        Some(format!(
+            "High {} (feels {}) / Low {} (feels {})",
+            self.format_temp(high, high_c, units), self.format_temp(feels_max.0, feels_max.1, units),
+            self.format_temp(low, low_c, units), self.format_temp(feels_min.0, feels_min.1, units)
+        ))
+    }
+
+    /// The lowest and highest hourly feels-like temperatures of a day, each as (°F, °C).
+    fn feels_like_extremes(&self, day: &Value) -> Option<((i32, i32), (i32, i32))> {
+        let feels: Vec<(i32, i32)> = day["hourly"].as_array().into_iter().flatten()
+            .filter_map(|hour| {
+                let f = hour["FeelsLikeF"].as_str()?.parse::<i32>().ok()?;
+                let c = hour["FeelsLikeC"].as_str()?.parse::<i32>().ok()?;
+                Some((f, c))
+            })
+            .collect();
+        Some((*feels.iter().min()?, *feels.iter().max()?))
+    }
+
+    fn format_temp(&self, temp_f: i32, temp_c: i32, units: Units) -> String {
+        match units {
+            Units::Imperial => format!("{}\u{00B0}F", temp_f),
+            Units::Metric => format!("{}\u{00B0}C", temp_c),
+            Units::Both => format!("{}\u{00B0}F/{}\u{00B0}C", temp_f, temp_c),
+        }
+    }
+
+    /// "(was 68°F, now 72°F, +4°F since last check)".
+    pub fn format_reading_change(&self, was: Reading, now: Reading, units: Units) -> String {
+        let (delta_f, delta_c) = (now.0 - was.0, now.1 - was.1);
+        let change = match units {
+            Units::Imperial => delta_f != 0,
+            Units::Metric => delta_c != 0,
+            Units::Both => delta_f != 0 || delta_c != 0,
+        };
+        let change = if change {
+            let signed = |delta: i32| if delta >= 0 { format!("+{}", delta) } else { delta.to_string() };
+            let delta = match units {
+                Units::Imperial => format!("{}\u{00B0}F", signed(delta_f)),
+                Units::Metric => format!("{}\u{00B0}C", signed(delta_c)),
+                Units::Both => format!("{}\u{00B0}F/{}\u{00B0}C", signed(delta_f), signed(delta_c)),
+            };
+            format!("{} since last check", delta)
+        } else {
+            "no change since last check".to_string()
+        };
+        format!(
+            "(was {}, now {}, {})",
+            self.format_temp(was.0, was.1, units), self.format_temp(now.0, now.1, units), change
+        )
+    }
+
+    /// Current and noon temperatures keep the Celsius value alongside in imperial mode.
+    fn format_spot_temp(&self, temp_f: i32, temp_c: i32, units: Units) -> String {
+        match units {
+            Units::Imperial => format!("{}\u{00B0}F {}\u{00B0}C", temp_f, temp_c),
+            _ => self.format_temp(temp_f, temp_c, units),
+        }
+    }
+
+    /// Applies --strip-variation-selectors to text containing emoji.
+    fn emoji(&self, text: &str) -> String {
+        if self.strip_variation_selectors {
+            text.replace('\u{FE0F}', "")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn get_emoji(&self, temp: i32) -> String {
+        let custom = {
+            let table = self.temp_emojis.lock().unwrap();
+            table.iter().rev().find(|(threshold, _)| temp >= *threshold).or(table.first())
+                .map(|(_, emoji)| format!("{} ", emoji))
+        };
+        if let Some(emoji) = custom {
+            return self.emoji(&emoji);
+        }
+        let emoji = if temp > 85 {
+            "🥵 "
+        } else if temp >= 70 {
+            "😎️ "
+        } else if temp < 32{
+            "🥶️ "
+        } else {
+            "🧥️ "
+        };
+        self.emoji(emoji)
+    }
+
+    fn get_condition_kind(&self, condition_code: i32) -> ConditionKind {
+        match condition_code {
+            113 => ConditionKind::Sunny,
+            116 => ConditionKind::PartlyCloudy,
+            119 | 122 => ConditionKind::Cloudy,  // Very Cloudy
+            143 | 248 | 260 => ConditionKind::Fog,
+            176 | 179 | 182 | 185 | 263 | 266 | 281 | 284 | 293 | 296 | 299 | 302 | 305 | 308 | 311 | 314 | 317 |
+            350 | 353 | 359 | 362 | 365 | 374 | 377 => ConditionKind::Rain,  // LightShowers to Light Sleet
+            200 | 386 | 389 => ConditionKind::Thunder,  // Thundery Showers
+            392 => ConditionKind::ThunderSnow,
+            227 | 320 | 323 | 326 | 368 => ConditionKind::Snow,
+            230 | 329 | 332 | 335 | 338 | 371 | 395 => ConditionKind::HeavySnow,
+            _ => ConditionKind::Unknown,  // Unknown/Unsupported Code
+        }
+    }
+
+    fn get_condition_emoji(&self, condition_code: i32) -> String {
+        self.kind_emoji(self.get_condition_kind(condition_code))
+    }
+
+    fn kind_emoji(&self, kind: ConditionKind) -> String {
+        let emoji = match kind {
+            ConditionKind::Sunny => "☀️",
+            ConditionKind::PartlyCloudy => "⛅️",
+            ConditionKind::Cloudy => "☁️",
+            ConditionKind::Fog => "🌫️",
+            ConditionKind::Rain => "🌧️",
+            ConditionKind::Thunder => "🌩️🌧️",
+            ConditionKind::ThunderSnow => "🌩️🌨️",
+            ConditionKind::Snow => "🌨️",
+            ConditionKind::HeavySnow => "🌨️❄️",
+            ConditionKind::Unknown => "✨",
+        };
+        self.emoji(emoji)
+    }
+
+    fn get_condition_color(&self, condition_code: i32) -> Option<String> {
+        self.condition_colors.lock().unwrap().get(&self.get_condition_kind(condition_code)).cloned()
+    }
+
+    fn color_condition(&self, condition_code: i32, conditions: &str) -> String {
+        match self.get_condition_color(condition_code) {
+            Some(color) => format!("\x03{}{}\x0F", color, conditions),
+            None => conditions.to_string(),
+        }
+    }
+
+    fn get_temp_color(&self, temp: i32) -> &'static str {
+        if temp > 85 {
+            "04"  // Red
+        } else if temp > 70 {
+            "07"  // Orange
+        } else if temp < 32 {
+            "12"  // Light Blue
+        } else {
+            "03"  // Green
+        }
+    }
+}
+
+/// What resolve_address has to look up remotely.
+enum AddressLookup {
+    /// The code (uppercased) and the locality it's relative to
+    ShortPlusCode(String, String),
+    What3Words(String),
+}
+
+/// Quotes a CSV field if it holds a comma, quote or line break, doubling any quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Splits a normalized query into a plus code and the locality after it, if any: "9C3XGV4C+XV"
+/// is a full code, "GV4C+XV+London" a short one. Short codes must have exactly two digits after
+/// the '+', which keeps postcodes like "WC2H 9JQ" (normalized to "WC2H+9JQ") from matching.
+fn parse_plus_code(query: &str) -> Option<(String, Option<String>)> {
+    let mut parts = query.splitn(3, '+');
+    let (before, after) = (parts.next()?.to_uppercase(), parts.next()?.to_uppercase());
+    let locality = parts.next().filter(|rest| !rest.is_empty()).map(str::to_string);
+    let valid = |s: &str| s.chars().all(|c| PLUS_CODE_ALPHABET.contains(c));
+    if !valid(&after) || after.len() > 3 {
+        return None;
+    }
+    let code = format!("{}+{}", before, after);
+    match before.len() {
+        // Padded codes ("9C3XGV00+") stand for a larger area and have nothing after the '+'.
+        8 if after.is_empty() && before.trim_end_matches('0').len() % 2 == 0 && valid(before.trim_end_matches('0')) => {
+            Some((code, None))
+        }
+        8 if valid(&before) => Some((code, None)),
+        4 | 6 if valid(&before) && after.len() == 2 => Some((code, Some(locality?))),
+        _ => None,
+    }
+}
+
+/// The centre of a full plus code's area as (latitude, longitude). Digits past the tenth only
+/// refine within ~14m, so they're ignored.
+fn decode_plus_code(code: &str) -> Option<(f64, f64)> {
+    let digits: Vec<usize> = code.chars()
+        .filter(|c| *c != '+' && *c != '0')
+        .take(10)
+        .map(|c| PLUS_CODE_ALPHABET.find(c))
+        .collect::<Option<_>>()?;
+    let (mut lat, mut lon, mut resolution) = (-90.0, -180.0, 20.0);
+    for pair in digits.chunks(2) {
+        lat += pair[0] as f64 * resolution;
+        lon += pair.get(1).copied().unwrap_or(0) as f64 * resolution;
+        resolution /= 20.0;
+    }
+    let half = resolution * 20.0 / 2.0;
+    let (lat, lon) = (lat + half, lon + half);
+    (lat <= 90.0 && lon <= 180.0).then_some((lat, lon))
+}
+
+/// The full code a short plus code stands for near a reference point: the reference's own
+/// leading digits are filled in, then the result is moved by one grid step if that lands
+/// closer to the reference (the open-location-code recoverNearest algorithm).
+fn recover_plus_code(code: &str, ref_lat: f64, ref_lon: f64) -> Option<(f64, f64)> {
+    let padding = 8 - code.find('+')?;
+    let resolution = 20f64.powi(2 - padding as i32 / 2);
+    let mut prefix = String::new();
+    let (mut lat, mut lon) = ((ref_lat + 90.0).clamp(0.0, 179.999_999), (ref_lon + 180.0).rem_euclid(360.0));
+    for place in 0..padding / 2 {
+        let step = 20f64.powi(1 - place as i32);
+        let (lat_digit, lon_digit) = ((lat / step).floor(), (lon / step).floor());
+        lat -= lat_digit * step;
+        lon -= lon_digit * step;
+        prefix.push(PLUS_CODE_ALPHABET.chars().nth(lat_digit as usize)?);
+        prefix.push(PLUS_CODE_ALPHABET.chars().nth(lon_digit as usize)?);
+    }
+    let (mut lat, mut lon) = decode_plus_code(&format!("{}{}", prefix, code))?;
+    let half = resolution / 2.0;
+    if ref_lat + half < lat && lat - resolution >= -90.0 {
+        lat -= resolution;
+    } else if ref_lat - half > lat && lat + resolution <= 90.0 {
+        lat += resolution;
+    }
+    if ref_lon + half < lon {
+        lon -= resolution;
+    } else if ref_lon - half > lon {
+        lon += resolution;
+    }
+    Some((lat, (lon + 180.0).rem_euclid(360.0) - 180.0))
+}
+
+/// "filled.count.soap", optionally after "///". Without the slashes each word must be three or
+/// more lowercase letters, so abbreviations like "Washington.D.C" aren't taken for one.
+fn is_what3words(query: &str) -> bool {
+    let (words, prefixed) = match query.strip_prefix("///") {
+        Some(words) => (words, true),
+        None => (query, false),
+    };
+    let words: Vec<&str> = words.split('.').collect();
+    words.len() == 3
+        && words.iter().all(|word| {
+            word.chars().all(char::is_alphabetic)
+                && ((prefixed && !word.is_empty()) || (word.chars().count() >= 3 && word.chars().all(char::is_lowercase)))
+        })
+}
+
+/// Translates mIRC bold (\x02), color (\x03fg[,bg]), italic (\x1D), underline (\x1F) and
+/// reset (\x0F) codes for `target`. Colors past 15 fall back to the terminal's default.
+pub fn render_colors(s: &str, target: ColorTarget) -> String {
+    let re_format = Regex::new(r"\x03(?:(\d{1,2})(?:,(\d{1,2}))?)?|[\x02\x0F\x1D\x1F]").unwrap();
+    if target == ColorTarget::Plain {
+        return re_format.replace_all(s, "").into_owned();
+    }
+    let ansi_color = |code: &str, offset: u8| match code.parse::<usize>() {
+        Ok(code) if code < ANSI_COLORS.len() => ANSI_COLORS[code] + offset,
+        _ => 39 + offset,
+    };
+    let (mut bold, mut italic, mut underline, mut styled) = (false, false, false, false);
+    let rendered = re_format.replace_all(s, |caps: &Captures| {
+        styled = true;
+        let codes = match &caps[0][..1] {
+            "\x02" => {
+                bold = !bold;
+                (if bold { "1" } else { "22" }).to_string()
+            }
+            "\x1D" => {
+                italic = !italic;
+                (if italic { "3" } else { "23" }).to_string()
+            }
+            "\x1F" => {
+                underline = !underline;
+                (if underline { "4" } else { "24" }).to_string()
+            }
+            "\x0F" => {
+                (bold, italic, underline) = (false, false, false);
+                "0".to_string()
+            }
+            _ => match (caps.get(1), caps.get(2)) {
+                (Some(fg), Some(bg)) => format!("{};{}", ansi_color(fg.as_str(), 0), ansi_color(bg.as_str(), 10)),
+                (Some(fg), None) => ansi_color(fg.as_str(), 0).to_string(),
+                (None, _) => "39;49".to_string(),
+            },
+        };
+        format!("\x1b[{}m", codes)
+    });
+    if styled {
+        format!("{}\x1b[0m", rendered)
+    } else {
+        rendered.into_owned()
+    }
+}
+
+/// A canned-response Fetcher and a bot wired to it, so tests never touch the network.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::cli::Args;
+    use clap::Parser;
+    use std::sync::Arc;
+
+    /// wttr.in's j1 response for London, observed at 3pm local time.
+    pub const LONDON_J1: &str = include_str!("testdata/london_j1.json");
+
+    /// Every URL a CannedFetcher was asked for, and the most it was serving at once.
+    #[derive(Default)]
+    pub struct FetchLog {
+        pub urls: Mutex<Vec<String>>,
+        /// (running now, peak)
+        concurrency: Mutex<(usize, usize)>,
+    }
+
+    impl FetchLog {
+        pub fn peak(&self) -> usize {
+            self.concurrency.lock().unwrap().1
+        }
+    }
+
+    /// Answers each URL with the first canned response whose pattern it contains; anything
+    /// else is a 404.
+    #[derive(Default)]
+    pub struct CannedFetcher {
+        responses: Vec<(String, HttpResponse)>,
+        delay: Duration,
+        pub log: Arc<FetchLog>,
+    }
+
+    impl CannedFetcher {
+        /// London's j1 response for every wttr.in URL.
+        pub fn london() -> Self {
+            CannedFetcher::default().respond("wttr.in", 200, LONDON_J1)
+        }
+
+        pub fn respond(mut self, pattern: &str, status: u16, body: &str) -> Self {
+            self.responses.push((pattern.to_string(), HttpResponse { status, retry_after: None, body: body.to_string() }));
+            self
+        }
+
+        /// A 429 carrying a Retry-After of `seconds`.
+        pub fn rate_limit(mut self, pattern: &str, seconds: u64) -> Self {
+            self.responses.push((pattern.to_string(), HttpResponse { status: 429, retry_after: Some(seconds), body: String::new() }));
+            self
+        }
+
+        /// Makes every request take `delay`, so concurrent fetches overlap.
+        pub fn delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+    }
+
+    impl Fetcher for CannedFetcher {
+        fn get(&self, url: &str) -> BoxFuture<'_, Result<HttpResponse, Box<dyn Error + Send + Sync>>> {
+            self.log.urls.lock().unwrap().push(url.to_string());
+            let response = self.responses.iter()
+                .find(|(pattern, _)| url.contains(pattern.as_str()))
+                .map_or(HttpResponse { status: 404, retry_after: None, body: String::new() }, |(_, response)| response.clone());
+            Box::pin(async move {
+                {
+                    let mut concurrency = self.log.concurrency.lock().unwrap();
+                    concurrency.0 += 1;
+                    concurrency.1 = concurrency.1.max(concurrency.0);
+                }
+                tokio::time::sleep(self.delay).await;
+                self.log.concurrency.lock().unwrap().0 -= 1;
+                Ok(response)
+            })
+        }
+    }
+
+    /// A client that talks to irc's in-memory mock connection instead of a server.
+    pub async fn mock_client() -> irc::client::Client {
+        let config = irc::client::prelude::Config {
+            nickname: Some("rirc".to_string()),
+            server: Some("irc.example.org".to_string()),
+            use_mock_connection: true,
+            ..Default::default()
+        };
+        irc::client::Client::from_config(config).await.unwrap()
+    }
+
+    /// A bot built from `flags` (a server and channel are supplied) that fetches from `fetcher`.
+    pub fn bot_with(flags: &[&str], fetcher: CannedFetcher) -> (WeatherBot, Arc<FetchLog>) {
+        let args = ["rirc", "--server", "irc.example.org", "--channel", "#weather"].iter().chain(flags);
+        let mut bot = WeatherBot::new(Args::parse_from(args)).unwrap();
+        let log = Arc::clone(&fetcher.log);
+        bot.fetcher = Box::new(fetcher);
+        (bot, log)
+    }
+
+    /// A bot built from `flags` that gets London's forecast for any wttr.in query.
+    pub fn bot(flags: &[&str]) -> WeatherBot {
+        bot_with(flags, CannedFetcher::london()).0
+    }
+
+    pub fn london() -> Value {
+        serde_json::from_str(LONDON_J1).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn canned_response_reaches_the_report() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london());
+        let report = bot.get_report("London", Report::Temp, Units::Metric, None, None).await.unwrap();
+        assert_eq!(report, "London: 21°C (feels 20°C), partly cloudy");
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=j1"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_stay_within_the_concurrency_limit() {
+        let fetcher = CannedFetcher::london().delay(Duration::from_millis(50));
+        let (bot, log) = bot_with(&["--max-concurrent-fetches", "2"], fetcher);
+        let cities = ["London", "Paris", "Berlin", "Madrid", "Rome", "Vienna"];
+        let results = futures_util::future::join_all(cities.iter().map(|city| bot.get_weather(city, None))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(log.urls.lock().unwrap().len(), cities.len());
+        assert_eq!(log.peak(), 2);
+    }
+
+    #[test]
+    fn dual_units_show_both_temperatures() {
+        let bot = bot(&[]);
+        assert_eq!(bot.format_temp(72, 22, Units::Both), "72°F/22°C");
+        assert_eq!(bot.format_temp(-4, -20, Units::Both), "-4°F/-20°C");
+        assert_eq!(bot.format_spot_temp(72, 22, Units::Both), "72°F/22°C");
+        let report = bot.format_temp_line(&bot.parse_forecast(&london()).unwrap(), "London", Units::Both);
+        assert_eq!(report, "London: 70°F/21°C (feels 68°F/20°C), partly cloudy");
+    }
+
+    #[test]
+    fn trend_rolls_into_tomorrow_in_every_unit() {
+        let bot = bot(&[]);
+        let response = london();
+        assert_eq!(bot.format_trend(&response, "London", Units::Imperial), "London: 73°F→66°F→61°F→55°F→57°F↓");
+        assert_eq!(bot.format_trend(&response, "London", Units::Metric), "London: 23°C→19°C→16°C→13°C→14°C↓");
+        assert_eq!(
+            bot.format_trend(&response, "London", Units::Both),
+            "London: 73°F/23°C→66°F/19°C→61°F/16°C→55°F/13°C→57°F/14°C↓"
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_blocks_further_fetches() {
+        let (bot, log) = bot_with(&[], CannedFetcher::default().rate_limit("wttr.in", 120));
+        let error = bot.get_weather("London", None).await.unwrap_err();
+        assert_eq!(error.to_string(), "wttr.in is rate limiting us, retry in 120s");
+        let remaining = bot.rate_limit_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(115) && remaining <= Duration::from_secs(120));
+
+        // Inside the window nothing more goes out to wttr.in.
+        let error = bot.get_weather("Paris", None).await.unwrap_err();
+        assert!(error.to_string().starts_with("wttr.in is rate limiting us, retry in "), "{}", error);
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=j1"]);
+    }
+
+    #[tokio::test]
+    async fn short_reports_use_the_one_line_format() {
+        let fetcher = CannedFetcher::default().respond("format=3", 200, "London: \x1b[33m⛅️\x1b[0m  +15°C\n");
+        let (bot, log) = bot_with(&[], fetcher);
+        let report = bot.get_report("London", Report::Short, Units::Metric, Some("de"), None).await.unwrap();
+        assert_eq!(report, "London: ⛅️  +15°C");
+        assert_eq!(*log.urls.lock().unwrap(), ["https://wttr.in/London?format=3&lang=de"]);
+    }
+
+    #[test]
+    fn every_temperature_carries_its_unit() {
+        let bot = bot(&[]);
+        let forecast = bot.parse_forecast(&london()).unwrap();
+        for units in [Units::Imperial, Units::Metric, Units::Both] {
+            let report = bot.format_response(&forecast, "London", 1200, units);
+            let bare: Vec<&str> = report.split('\u{00B0}').skip(1)
+                .filter(|rest| !rest.starts_with(['F', 'C']))
+                .collect();
+            assert!(bare.is_empty(), "{:?}: {}", units, report);
+        }
+        let report = bot.format_response(&forecast, "London", 1200, Units::Imperial);
+        assert!(report.contains("70\u{00B0}F 21\u{00B0}C"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_queries_share_one_fetch() {
+        let (bot, log) = bot_with(&[], CannedFetcher::london().delay(Duration::from_millis(50)));
+        let results = futures_util::future::join_all((0..5).map(|_| bot.get_weather("London", None))).await;
+        assert!(results.iter().all(|result| result.as_ref().is_ok_and(|data| data["current_condition"][0]["temp_C"] == "21")));
+        assert_eq!(log.urls.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_second_wave_right_after_a_fetch_is_not_cut_off() {
+        let (bot, _) = bot_with(&[], CannedFetcher::london().delay(Duration::from_millis(5)));
+        let bot = std::sync::Arc::new(bot);
+        for _ in 0..50 {
+            // Each task asks again the moment its first answer arrives, racing the first
+            // fetch's cleanup on another worker thread.
+            let tasks: Vec<_> = (0..4).map(|_| {
+                let bot = std::sync::Arc::clone(&bot);
+                tokio::spawn(async move {
+                    let first = bot.get_weather("London", None).await.map_err(|e| e.to_string());
+                    let second = bot.get_weather("London", None).await.map_err(|e| e.to_string());
+                    (first, second)
+                })
+            }).collect();
+            for task in tasks {
+                let (first, second) = task.await.unwrap();
+                assert!(first.is_ok() && second.is_ok(), "{:?} {:?}", first.err(), second.err());
+            }
+        }
+        assert!(bot.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_compares_against_home() {
+        let bot = bot(&[]);
+        let home = london();
+        let mut paris = london();
+        paris["nearest_area"][0]["areaName"][0]["value"] = json!("Paris");
+        paris["current_condition"][0]["temp_F"] = json!("75");
+        paris["current_condition"][0]["temp_C"] = json!("24");
+        paris["current_condition"][0]["weatherDesc"][0]["value"] = json!("Sunny");
+        assert_eq!(
+            bot.format_diff(&home, "London", &paris, "Paris", Units::Imperial),
+            "Paris is 5°F warmer than London (Sunny vs Partly cloudy)"
+        );
+        assert_eq!(
+            bot.format_diff(&paris, "Paris", &home, "London", Units::Both),
+            "London is 5°F/3°C colder than Paris (Partly cloudy vs Sunny)"
+        );
+        assert_eq!(
+            bot.format_diff(&home, "London", &home, "London", Units::Metric),
+            "London is the same temperature as London (Partly cloudy vs Partly cloudy)"
+        );
+    }
+
+    #[test]
+    fn json_output_schema() {
+        let bot = bot(&[]);
+        let forecast = bot.parse_forecast(&london()).unwrap();
+        let day = |date: &str, high: (i32, i32), low: (i32, i32)| json!({
+            "date": date,
+            "high_f": high.0, "high_c": high.1,
+            "low_f": low.0, "low_c": low.1,
+            "conditions": "Sunny", "weather_code": 113,
+        });
+        assert_eq!(bot.normalize_weather(&forecast, "London"), json!({
+            "location": "London",
+            "current": { "temp_f": 70, "temp_c": 21, "humidity": 55, "conditions": "Partly cloudy", "weather_code": 116 },
+            "forecast": [
+                day("2026-10-15", (75, 24), (58, 14)),
+                day("2026-10-16", (72, 22), (55, 13)),
+                day("2026-10-17", (68, 20), (50, 10)),
+            ],
+        }));
+    }
+
+    #[tokio::test]
+    async fn geocoded_coordinates_reach_wttr() {
+        let fetcher = CannedFetcher::london()
+            .respond("name=Springfield", 200, r#"{"results": [{"name": "Springfield", "latitude": 39.8, "longitude": -89.65}]}"#)
+            .respond("geocoding-api", 200, r#"{"generationtime_ms": 0.5}"#);
+        let (bot, log) = bot_with(&["--geocode"], fetcher);
+        bot.get_report("Springfield", Report::Temp, Units::Imperial, None, None).await.unwrap();
+        bot.get_report("Springfield", Report::Short, Units::Imperial, None, None).await.unwrap();
+        // A name the geocoder doesn't know goes to wttr.in as typed.
+        bot.get_report("Nowhere", Report::Temp, Units::Imperial, None, None).await.unwrap();
+        assert_eq!(*log.urls.lock().unwrap(), [
+            "https://geocoding-api.open-meteo.com/v1/search?name=Springfield&count=1",
+            "https://wttr.in/39.8,-89.65?format=j1",
+            "https://wttr.in/39.8,-89.65?format=3",
+            "https://geocoding-api.open-meteo.com/v1/search?name=Nowhere&count=1",
+            "https://wttr.in/Nowhere?format=j1",
+        ]);
+    }
+
+    #[test]
+    fn detailed_report_respects_units_and_skips_missing_fields() {
+        let bot = bot(&[]);
+        let mut response = london();
+        let detailed = |response: &Value, units| render_colors(&bot.format_detailed(response, "London", units, None), ColorTarget::Plain);
+        assert_eq!(
+            detailed(&response, Units::Imperial),
+            "London: ⛅️ Partly cloudy. Temp: 70°F 21°C. High 75°F (feels 73°F) / Low 58°F (feels 56°F). Humidity: 55%. \
+             Wind: 9 mph ↙. Gusts: 15 mph. Pressure: 30 inHg. Visibility: 6 mi. Sunrise: 07:21. Sunset: 18:05"
+        );
+        assert_eq!(
+            detailed(&response, Units::Metric),
+            "London: ⛅️ Partly cloudy. Temp: 21°C. High 24°C (feels 23°C) / Low 14°C (feels 13°C). Humidity: 55%. \
+             Wind: 14 km/h ↙. Gusts: 24 km/h. Pressure: 1015 hPa. Visibility: 10 km. Sunrise: 07:21. Sunset: 18:05"
+        );
+
+        let current = response["current_condition"][0].as_object_mut().unwrap();
+        for key in ["pressure", "pressureInches", "visibility", "visibilityMiles"] {
+            current.remove(key);
+        }
+        assert_eq!(
+            detailed(&response, Units::Metric),
+            "London: ⛅️ Partly cloudy. Temp: 21°C. High 24°C (feels 23°C) / Low 14°C (feels 13°C). Humidity: 55%. \
+             Wind: 14 km/h ↙. Gusts: 24 km/h. Sunrise: 07:21. Sunset: 18:05"
+        );
+    }
+
+    #[test]
+    fn requested_hours_map_to_the_nearest_entry() {
+        let bot = bot(&[]);
+        for (hour, index) in [(0, 0), (1, 0), (2, 1), (3, 1), (12, 4), (13, 4), (14, 5), (21, 7), (22, 7), (23, 7)] {
+            assert_eq!(bot.hourly_index(hour), index, "{}:00", hour);
+        }
+    }
+
+    #[test]
+    fn rain_answers_yes_or_no() {
+        let bot = bot(&[]);
+        let mut response = london();
+        assert_eq!(bot.format_rain(&response, "London", None), "London: Yes — 50% around 15:00");
+
+        for hour in response["weather"][0]["hourly"].as_array_mut().unwrap() {
+            hour["chanceofrain"] = json!("20");
+        }
+        assert_eq!(bot.format_rain(&response, "London", None), "London: No rain expected today");
+    }
+
+    #[test]
+    fn variation_selectors_can_be_stripped() {
+        let keep = bot(&[]);
+        let strip = bot(&["--strip-variation-selectors"]);
+        for code in [113, 116, 200, 389] {
+            assert!(keep.get_condition_emoji(code).contains('\u{FE0F}'), "{}", code);
+            assert!(!strip.get_condition_emoji(code).contains('\u{FE0F}'), "{}", code);
+            assert_eq!(strip.get_condition_emoji(code), keep.get_condition_emoji(code).replace('\u{FE0F}', ""));
+        }
+        for temp in [20, 60, 90] {
+            assert!(!strip.get_emoji(temp).contains('\u{FE0F}'), "{}", temp);
+        }
+    }
+
+    #[test]
+    fn wind_degrees_map_to_arrows() {
+        let plain = bot(&["--no-wind-arrows"]);
+        let bot = bot(&[]);
+        for (degrees, arrow) in [
+            (0, "↑"), (22, "↑"), (23, "↗"), (45, "↗"), (90, "→"), (135, "↘"), (180, "↓"),
+            (225, "↙"), (270, "←"), (315, "↖"), (337, "↖"), (338, "↑"), (360, "↑"), (-90, "←"),
+        ] {
+            assert_eq!(bot.wind_arrow(degrees), arrow, "{}°", degrees);
+        }
+
+        let detailed = render_colors(&plain.format_detailed(&london(), "London", Units::Metric, None), ColorTarget::Plain);
+        assert!(detailed.contains("Wind: 14 km/h SW."), "{}", detailed);
+    }
+
+    #[test]
+    fn comfort_follows_the_dew_point() {
+        let picky = bot(&["--dry-dewpoint", "55", "--muggy-dewpoint", "60"]);
+        let bot = bot(&[]);
+        for (temp_c, humidity, comfort) in [(21, 55, "comfortable"), (30, 80, "muggy"), (10, 40, "dry"), (30, 30, "comfortable"), (-5, 90, "dry")] {
+            assert_eq!(bot.comfort(temp_c, humidity), Some(comfort), "{}°C at {}%", temp_c, humidity);
+        }
+        assert_eq!(bot.comfort(21, 0), None);
+        assert_eq!(bot.comfort(21, 101), None);
+
+        assert_eq!(picky.comfort(21, 55), Some("dry"));
+        assert_eq!(picky.comfort(24, 65), Some("muggy"));
+    }
+
+    #[test]
+    fn degree_days_from_the_high_and_low() {
+        let cool_base = bot(&["--degree-day-base", "70"]);
+        let bot = bot(&[]);
+        assert_eq!(bot.degree_days(75, 58), (0.0, 1.5));
+        assert_eq!(bot.degree_days(50, 30), (25.0, 0.0));
+        assert_eq!(bot.degree_days(70, 60), (0.0, 0.0));
+        assert_eq!(cool_base.degree_days(75, 58), (3.5, 0.0));
+
+        let mut response = london();
+        assert_eq!(bot.format_degree_days(&response, "London"), "London: 0 HDD, 1.5 CDD today (base 65°F)");
+        response["weather"][0].as_object_mut().unwrap().remove("mintempF");
+        assert_eq!(bot.format_degree_days(&response, "London"), "London: degree days unavailable");
+    }
+
+    #[test]
+    fn snow_report_for_a_snowy_forecast() {
+        let bot = bot(&[]);
+        let mut response = london();
+        response["weather"][0]["totalSnow_cm"] = json!("12.7");
+        response["weather"][0]["hourly"][4]["chanceofsnow"] = json!("80");
+        response["weather"][1]["totalSnow_cm"] = json!("2.5");
+        response["weather"][1]["hourly"][2]["chanceofsnow"] = json!("30");
+        response["weather"][2].as_object_mut().unwrap().remove("totalSnow_cm");
+        assert_eq!(bot.format_snow(&response, "London", Units::Imperial), "London: Today: 5.0 in (80% chance) | Tomorrow: 1.0 in (30% chance)");
+        assert_eq!(bot.format_snow(&response, "London", Units::Metric), "London: Today: 12.7 cm (80% chance) | Tomorrow: 2.5 cm (30% chance)");
+        assert_eq!(
+            bot.format_snow(&response, "London", Units::Both),
+            "London: Today: 5.0 in/12.7 cm (80% chance) | Tomorrow: 1.0 in/2.5 cm (30% chance)"
+        );
+
+        for day in response["weather"].as_array_mut().unwrap() {
+            day.as_object_mut().unwrap().remove("totalSnow_cm");
+        }
+        assert_eq!(bot.format_snow(&response, "London", Units::Metric), "London: no snowfall forecast available");
+    }
+
+    #[test]
+    fn missing_tomorrow_still_renders_today() {
+        let bot = bot(&[]);
+        let mut response = london();
+        response["weather"][1] = json!({});
+        response["weather"][0].as_object_mut().unwrap().remove("astronomy");
+        let forecast = bot.parse_forecast(&response).unwrap();
+        let report = render_colors(&bot.format_response(&forecast, "London", 12, Units::Metric), ColorTarget::Plain);
+        assert_eq!(
+            report,
+            "London: Conditions: ⛅️ Partly cloudy. Humidity: 55% (comfortable). Temp: 😎️ 21°C. High: 😎️ 24°C. Low: 🧥️ 14°C \
+             | Day After: Conditions: ☀️Sunny. Humidity: 60%. Noon: 🧥️ 20°C. High: 🧥️ 20°C. Low: 🧥️ 10°C"
+        );
+    }
+
+    #[tokio::test]
+    async fn marine_data_missing_inland() {
+        let fetcher = CannedFetcher::london()
+            .respond("latitude=51.517", 400, r#"{"error": true, "reason": "No data is available for this location"}"#);
+        let (bot, log) = bot_with(&["--marine"], fetcher);
+        let report = bot.get_report("London", Report::Marine, Units::Metric, None, None).await.unwrap();
+        assert_eq!(report, "Marine data isn't available for London");
+        assert_eq!(log.urls.lock().unwrap()[1], "https://marine-api.open-meteo.com/v1/marine?latitude=51.517&longitude=-0.106&current=sea_surface_temperature%2Cwave_height");
+
+        let null_readings = json!({"current": {"sea_surface_temperature": null, "wave_height": null}});
+        assert_eq!(bot.format_marine("London", &null_readings, Units::Metric), "Marine data isn't available for London");
+
+        let (bot, _) = bot_with(&[], CannedFetcher::london());
+        let error = bot.get_report("London", Report::Marine, Units::Metric, None, None).await.unwrap_err();
+        assert_eq!(error.to_string(), "marine reports aren't enabled on this bot.");
+    }
+
+    #[test]
+    fn todays_entry_is_the_next_upcoming_hour() {
+        let bot = bot(&[]);
+        let upcoming = |observed: Option<&str>| {
+            let mut response = london();
+            match observed {
+                Some(observed) => response["current_condition"][0]["localObsDateTime"] = json!(observed),
+                None => { response["current_condition"][0].as_object_mut().unwrap().remove("localObsDateTime"); }
+            }
+            bot.upcoming_hourly(&bot.parse_forecast(&response).unwrap()).map(|hour| hour.time)
+        };
+        assert_eq!(upcoming(Some("2026-10-15 03:00 PM")), Some(1800));
+        assert_eq!(upcoming(Some("2026-10-15 07:40 AM")), Some(900));
+        assert_eq!(upcoming(Some("2026-10-15 11:00 PM")), Some(2100));
+        assert_eq!(upcoming(None), Some(1200));
+    }
+
+    #[test]
+    fn feels_like_extremes_from_the_hourly_entries() {
+        let bot = bot(&[]);
+        let mut day = london()["weather"][0].clone();
+        assert_eq!(bot.feels_like_extremes(&day), Some(((56, 13), (73, 23))));
+
+        day["hourly"][4]["FeelsLikeF"] = json!("95");
+        day["hourly"][4]["FeelsLikeC"] = json!("35");
+        day["hourly"][1]["FeelsLikeF"] = json!("48");
+        day["hourly"][1]["FeelsLikeC"] = json!("9");
+        // An entry without a feels-like reading is skipped.
+        day["hourly"][0].as_object_mut().unwrap().remove("FeelsLikeF");
+        assert_eq!(bot.feels_like_extremes(&day), Some(((48, 9), (95, 35))));
+        assert_eq!(
+            bot.format_feels_like_range(&day, Units::Imperial).as_deref(),
+            Some("High 75°F (feels 95°F) / Low 58°F (feels 48°F)")
+        );
+    }
+
+    #[test]
+    fn sample_response_parses_into_a_forecast() {
+        let bot = bot(&[]);
+        let forecast = bot.parse_forecast(&london()).unwrap();
+        assert_eq!(forecast.location.as_deref(), Some("London"));
+        assert_eq!(forecast.local_time, Some(1500));
+        assert_eq!(forecast.current, Some(CurrentConditions {
+            temp: Temp { f: 70, c: 21 },
+            feels_like: Some(Temp { f: 68, c: 20 }),
+            humidity: Some(55),
+            conditions: Conditions { description: "Partly cloudy".to_string(), code: 116 },
+        }));
+        let dates: Vec<_> = forecast.days.iter().map(|day| day.date.as_deref()).collect();
+        assert_eq!(dates, [Some("2026-10-15"), Some("2026-10-16"), Some("2026-10-17")]);
+        let today = &forecast.days[0];
+        assert_eq!((today.high, today.low, today.normal_high), (Some(Temp { f: 75, c: 24 }), Some(Temp { f: 58, c: 14 }), None));
+        assert_eq!(today.hourly.len(), 8);
+        assert_eq!(today.hourly[4], HourlyForecast {
+            time: 1200,
+            temp: Temp { f: 75, c: 24 },
+            humidity: Some(60),
+            conditions: Conditions { description: "Sunny".to_string(), code: 113 },
+        });
+
+        assert!(bot.parse_forecast(&json!({"error": "Unknown location"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn highs_are_compared_with_the_climate_normal() {
+        let archive = json!({"daily": {
+            "time": ["2019-10-15", "2020-10-15", "2020-10-16"],
+            "temperature_2m_max": [18.0, 20.0, 30.0],
+        }});
+        let fetcher = CannedFetcher::london().respond("archive-api", 200, &archive.to_string());
+        let (bot, log) = bot_with(&["--climate-normals"], fetcher);
+        let report = bot.get_report("London", Report::Full { hour: None }, Units::Imperial, None, None).await.unwrap();
+        // 75°F against a 19°C (66°F) average.
+        assert!(report.contains("(+9°F vs normal)"), "{}", report);
+        assert!(log.urls.lock().unwrap()[1].starts_with("https://archive-api.open-meteo.com/v1/archive?latitude=51.517&longitude=-0.106&"));
+
+        let (bot, _) = bot_with(&["--climate-normals"], CannedFetcher::london().respond("archive-api", 503, ""));
+        let report = bot.get_report("London", Report::Full { hour: None }, Units::Imperial, None, None).await.unwrap();
+        assert!(!report.contains("vs normal"), "{}", report);
+    }
+
+    #[test]
+    fn dominant_precipitation_wins() {
+        let bot = bot(&[]);
+        let mut response = london();
+        // Today: snow beats the 70% rain; tomorrow: a tie goes to snow; day after: nothing.
+        response["weather"][0]["hourly"][6]["chanceofsnow"] = json!("85");
+        response["weather"][1]["hourly"][2]["chanceofsnow"] = json!("70");
+        for hour in response["weather"][2]["hourly"].as_array_mut().unwrap() {
+            hour["chanceofrain"] = json!("0");
+        }
+        let precip = bot.format_precip(&response, "London");
+        assert_eq!(precip, "London: Today: 🌨️ 85% snow | Tomorrow: 🌨️ 70% snow | Day After: none expected");
+    }
+
+    #[test]
+    fn custom_temperature_emoji() {
+        let themed = bot(&["--temp-emoji", "86=🔥", "--temp-emoji", "40=🍂", "--temp-emoji", "60=🌿"]);
+        let bot = bot(&[]);
+        for (temp, emoji) in [(100, "🔥 "), (86, "🔥 "), (85, "🌿 "), (60, "🌿 "), (59, "🍂 "), (40, "🍂 "), (-10, "🍂 ")] {
+            assert_eq!(themed.get_emoji(temp), emoji, "{}°F", temp);
+        }
+        assert_eq!(bot.get_emoji(90), "🥵 ");
+        assert_eq!(bot.get_emoji(20), "🥶️ ");
+    }
+
+    #[tokio::test]
+    async fn day_offset_picks_one_forecast_day() {
+        let bot = bot(&[]);
+        let tomorrow = bot.get_report("London", Report::Day { offset: 1 }, Units::Metric, None, None).await.unwrap();
+        assert!(tomorrow.starts_with("London: Tomorrow (2026-10-16): "), "{}", tomorrow);
+        assert!(tomorrow.contains("22°C") && tomorrow.contains("13°C"), "{}", tomorrow);
+        let past_the_end = bot.get_report("London", Report::Day { offset: 3 }, Units::Metric, None, None).await;
+        assert_eq!(past_the_end.unwrap_err().to_string(), "only days 0-2 are available");
+    }
+
+    #[test]
+    fn severe_prefix_is_bold_red() {
+        let prefix = bot(&[]).format_severe_prefix();
+        assert_eq!(prefix, "\x02\x0304\u{26A0}\u{FE0F} Severe weather\x0F");
+        assert_eq!(render_colors(&prefix, ColorTarget::Plain), "\u{26A0}\u{FE0F} Severe weather");
+        let stripped = bot(&["--strip-variation-selectors"]).format_severe_prefix();
+        assert_eq!(render_colors(&stripped, ColorTarget::Plain), "\u{26A0} Severe weather");
+    }
+
+    #[test]
+    fn temp_line_is_one_compact_line() {
+        let bot = bot(&[]);
+        let forecast = bot.parse_forecast(&london()).unwrap();
+        assert_eq!(bot.format_temp_line(&forecast, "London", Units::Imperial), "London: 70°F (feels 68°F), partly cloudy");
+        assert_eq!(bot.format_temp_line(&forecast, "London", Units::Metric), "London: 21°C (feels 20°C), partly cloudy");
+        let mut response = london();
+        response.as_object_mut().unwrap().remove("current_condition");
+        let forecast = bot.parse_forecast(&response).unwrap();
+        assert_eq!(bot.format_temp_line(&forecast, "London", Units::Metric), "London: current conditions unavailable");
+    }
+
+    #[test]
+    fn colors_render_as_ansi_or_plain() {
+        let colored = "\x0304hot\x0F and \x02bold\x02 \x0312,01x";
+        assert_eq!(
+            render_colors(colored, ColorTarget::Ansi),
+            "\x1b[91mhot\x1b[0m and \x1b[1mbold\x1b[22m \x1b[94;40mx\x1b[0m"
+        );
+        assert_eq!(render_colors(colored, ColorTarget::Plain), "hot and bold x");
+        assert_eq!(render_colors("London: 21°C", ColorTarget::Ansi), "London: 21°C");
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_after_failures_and_recovers() {
+        let fetcher = CannedFetcher::default().respond("Down", 500, "").respond("wttr.in", 200, LONDON_J1);
+        let (bot, log) = bot_with(&["--breaker-threshold", "2", "--breaker-cooldown", "1"], fetcher);
+        let fetches = || log.urls.lock().unwrap().len();
+
+        bot.get_weather("Down", None).await.unwrap_err();
+        assert_eq!(*bot.circuit.lock().unwrap(), CircuitState::Closed { failures: 1 });
+        bot.get_weather("Down", None).await.unwrap_err();
+        assert!(matches!(*bot.circuit.lock().unwrap(), CircuitState::Open { .. }));
+
+        // Open: nothing reaches wttr.in, even for a location that would work.
+        let error = bot.get_weather("London", None).await.unwrap_err();
+        assert_eq!(error.to_string(), "the weather service is currently down");
+        assert_eq!(fetches(), 2);
+
+        // After the cooldown one request goes through as the test, and its success closes the circuit.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(bot.circuit_allows());
+        assert_eq!(*bot.circuit.lock().unwrap(), CircuitState::HalfOpen);
+        assert!(!bot.circuit_allows());
+        bot.record_fetch(true);
+        assert_eq!(*bot.circuit.lock().unwrap(), CircuitState::Closed { failures: 0 });
+        bot.get_weather("London", None).await.unwrap();
+        assert_eq!(fetches(), 3);
+    }
+
+    #[test]
+    fn table_columns_line_up() {
+        let bot = bot(&[]);
+        let mut forecast = bot.parse_forecast(&london()).unwrap();
+        for hour in &mut forecast.days[1].hourly {
+            hour.conditions.description = "Light rain".to_string();
+        }
+        // Each column is as wide as its widest cell, "Light rain" here, with two spaces between.
+        assert_eq!(bot.format_table(&forecast, "London", Units::Metric), [
+            "London",
+            "      Today  Fri         Sat",
+            "High  24°C   22°C        20°C",
+            "Low   14°C   13°C        10°C",
+            "Cond  Sunny  Light rain  Sunny",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn current_without_forecast_days() {
+        let bot = bot(&[]);
+        let mut response = london();
+        response["weather"] = json!([]);
+        let forecast = bot.parse_forecast(&response).unwrap();
+        let report = render_colors(&bot.format_response(&forecast, "London", 12, Units::Metric), ColorTarget::Plain);
+        assert_eq!(
+            report,
+            "London: Conditions: \u{26C5}\u{FE0F} Partly cloudy. Humidity: 55% (comfortable). Temp: \u{1F60E}\u{FE0F} 21°C (forecast unavailable)"
+        );
+        response.as_object_mut().unwrap().remove("weather");
+        let forecast = bot.parse_forecast(&response).unwrap();
+        assert!(bot.format_response(&forecast, "London", 12, Units::Metric).ends_with(" (forecast unavailable)"));
+    }
+
+    #[test]
+    fn clock_converts_to_the_home_timezone() {
+        let bot = bot(&[]);
+        let sunset = NaiveTime::from_hms_opt(18, 5, 0).unwrap();
+        // London's fixture is observed at 3pm local, 2pm UTC: BST, an hour ahead.
+        assert_eq!(bot.format_clock(&london(), sunset, None), "18:05");
+        assert_eq!(bot.format_clock(&london(), sunset, Some(chrono_tz::America::New_York)), "13:05 EDT");
+        assert_eq!(bot.format_clock(&london(), sunset, Some(chrono_tz::Asia::Tokyo)), "02:05 JST");
+        let mut unknown_offset = london();
+        unknown_offset["current_condition"][0].as_object_mut().unwrap().remove("observation_time");
+        assert_eq!(bot.format_clock(&unknown_offset, sunset, Some(chrono_tz::America::New_York)), "18:05");
+    }
+
+    #[test]
+    fn ranges_only() {
+        let bot = bot(&[]);
+        let mut forecast = bot.parse_forecast(&london()).unwrap();
+        assert_eq!(bot.format_ranges(&forecast, "London", Units::Imperial), "London: Today 58–75°F, Tomorrow 55–72°F, Sat 50–68°F");
+        assert_eq!(bot.format_ranges(&forecast, "London", Units::Metric), "London: Today 14–24°C, Tomorrow 13–22°C, Sat 10–20°C");
+        forecast.days[1].low = None;
+        assert_eq!(bot.format_ranges(&forecast, "London", Units::Both), "London: Today 58–75°F/14–24°C, Sat 50–68°F/10–20°C");
+        forecast.days.clear();
+        assert_eq!(bot.format_ranges(&forecast, "London", Units::Metric), "London: no forecast ranges available");
+    }
+
+    #[test]
+    fn extremes_span_every_day() {
+        let bot = bot(&[]);
+        let forecast = bot.parse_forecast(&london()).unwrap();
+        let extremes = render_colors(&bot.format_extremes(&forecast, "London", Units::Imperial), ColorTarget::Plain);
+        // Today's 75°F at noon is already past the 3pm observation, so 73°F at 15:00 is warmest.
+        assert_eq!(extremes, "London: Warmest 73°F today 15:00, coolest 50°F Sat 00:00");
+        let mut forecast = forecast;
+        forecast.days.truncate(1);
+        forecast.local_time = Some(2400);
+        assert_eq!(bot.format_extremes(&forecast, "London", Units::Imperial), "London: no hourly forecast available");
+    }
+
+    #[test]
+    fn forecast_days_are_capped() {
+        let mut week = london();
+        let day = week["weather"][0].clone();
+        week["weather"] = (0..7).map(|offset| {
+            let mut day = day.clone();
+            day["date"] = json!(format!("2026-10-{}", 15 + offset));
+            day
+        }).collect();
+        let five = bot(&["--max-forecast-days", "5"]);
+        let bot = bot(&[]);
+        assert_eq!(bot.parse_forecast(&week).unwrap().days.len(), 3);
+        assert_eq!(
+            bot.format_ranges(&bot.parse_forecast(&week).unwrap(), "London", Units::Metric),
+            "London: Today 14–24°C, Tomorrow 14–24°C, Sat 14–24°C"
+        );
+        assert_eq!(
+            five.format_ranges(&five.parse_forecast(&week).unwrap(), "London", Units::Metric),
+            "London: Today 14–24°C, Tomorrow 14–24°C, Sat 14–24°C, Sun 14–24°C, Mon 14–24°C"
+        );
+    }
+
+    #[test]
+    fn wear_advice_follows_the_thresholds() {
+        let bot = bot(&[]);
+        assert_eq!(bot.wear_advice(40, 5, 0), ["bring a jacket"]);
+        assert_eq!(bot.wear_advice(65, 5, 10), ["light layers"]);
+        assert_eq!(bot.wear_advice(85, 25, 80), ["shorts weather", "something windproof", "umbrella recommended"]);
+        let hardy = bot_with(&["--jacket-below", "30"], CannedFetcher::london()).0;
+        assert_eq!(hardy.wear_advice(40, 5, 0), ["light layers"]);
+
+        let wear = render_colors(&bot.format_wear(&london(), "London", Units::Imperial), ColorTarget::Plain);
+        // Feels 68°F with 9 mph of wind, and up to 70% chance of rain later today.
+        assert_eq!(wear, "London: 70°F, partly cloudy — light layers; umbrella recommended");
+    }
+
+    #[test]
+    fn csv_columns_in_order() {
+        let bot = bot(&[]);
+        let mut forecast = bot.parse_forecast(&london()).unwrap();
+        forecast.location = Some("London, \"City\"".to_string());
+        // location,date,high_f,high_c,low_f,low_c,conditions; the comma and quotes get the location quoted.
+        assert_eq!(bot.format_csv(&forecast, "London"), [
+            r#""London, ""City""",2026-10-15,75,24,58,14,Sunny"#,
+            r#""London, ""City""",2026-10-16,72,22,55,13,Sunny"#,
+            r#""London, ""City""",2026-10-17,68,20,50,10,Sunny"#,
+        ].join("\n"));
+        forecast.days.clear();
+        assert_eq!(bot.format_csv(&forecast, "London"), "London, \"City\": no forecast days are available");
+    }
+}